@@ -11,17 +11,19 @@ extern crate quick_error;
 extern crate tempdir;
 
 use std::cmp::max;
+use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fmt::Write as FmtWrite;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write as IoWrite;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use ansi_term::Style;
-use chrono::offset::TimeZone;
-use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
-use git2::{Config, Commit, Delta, Diff, Object, ObjectType, Oid, Reference, Repository, Tree, TreeBuilder};
+use chrono::offset::{Offset, TimeZone};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, Shell, SubCommand};
+use git2::{Config, Commit, Delta, Diff, DiffFindOptions, DiffOptions, Object, ObjectType, Oid, Reference, Repository, Tree, TreeBuilder, TreeEntry};
 use tempdir::TempDir;
 
 quick_error! {
@@ -48,11 +50,48 @@ quick_error! {
             cause(err)
             display("{}", err)
         }
+        Usage(err: clap::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+        }
+        CheckoutConflict(msg: String) {
+            description(msg)
+            display("{}", msg)
+        }
+        CommitNoChanges(status: String) {
+            description(status)
+            display("{}", status)
+        }
+    }
+}
+
+impl Error {
+    /// The process exit status for this error, as documented in the EXIT STATUS section of the
+    /// man page.
+    fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Usage(_) => 2,
+            Error::CheckoutConflict(_) => 3,
+            Error::CommitNoChanges(_) => 4,
+            // Match git's exit status for fatal errors.
+            Error::Git2(_) => 128,
+            Error::IO(_) | Error::Msg(_) | Error::Utf8Error(_) => 1,
+        }
     }
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// How much informational output to print, set by the global --quiet and --verbose options.
+/// Errors and output requested by the command itself, such as status, always appear.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
 const COMMIT_MESSAGE_COMMENT: &'static str = "
 # Please enter the commit message for your changes. Lines starting
 # with '#' will be ignored, and an empty message aborts the commit.
@@ -61,6 +100,11 @@ const COVER_LETTER_COMMENT: &'static str = "
 # Please enter the cover letter for your changes. Lines starting
 # with '#' will be ignored, and an empty message aborts the change.
 ";
+const DESCRIPTION_COMMENT: &'static str = "
+# Please enter a description of the patch series. The description is
+# never included in formatted patches or pull requests. Lines starting
+# with '#' will be ignored, and an empty message aborts the change.
+";
 const REBASE_COMMENT: &'static str = "\
 #
 # Commands:
@@ -91,6 +135,7 @@ const SERIES_PREFIX: &'static str = "refs/heads/git-series/";
 const SHEAD_REF: &'static str = "refs/SHEAD";
 const STAGED_PREFIX: &'static str = "refs/git-series-internals/staged/";
 const WORKING_PREFIX: &'static str = "refs/git-series-internals/working/";
+const UNDO_REF: &'static str = "refs/git-series-internals/undo";
 
 const GIT_FILEMODE_BLOB: u32 = 0o100644;
 const GIT_FILEMODE_COMMIT: u32 = 0o160000;
@@ -103,26 +148,85 @@ fn peel_to_commit(r: Reference) -> Result<Commit> {
     Ok(try!(try!(r.peel(ObjectType::Commit)).into_commit().map_err(|obj| format!("Internal error: expected a commit: {}", obj.id()))))
 }
 
-fn commit_obj_summarize_components(commit: &mut Commit) -> Result<(String, String)> {
-    let short_id_buf = try!(commit.as_object().short_id());
-    let short_id = short_id_buf.as_str().unwrap();
-    let summary = String::from_utf8_lossy(commit.summary_bytes().unwrap());
-    Ok((short_id.to_string(), summary.to_string()))
+// Decode commit message text for display. Honor a Latin-1 "encoding" header; otherwise assume
+// UTF-8, replacing any invalid sequences rather than failing.
+fn decode_message(bytes: &[u8], encoding: Option<&str>) -> String {
+    match encoding.map(|e| e.to_lowercase()) {
+        Some(ref e) if ["iso-8859-1", "iso8859-1", "latin1", "latin-1"].contains(&&e[..]) => {
+            bytes.iter().map(|&b| b as char).collect()
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+#[test]
+fn test_decode_message() {
+    let tests: Vec<(&[u8], Option<&str>, &str)> = vec![
+        (b"Test", None, "Test"),
+        (b"Caf\xc3\xa9", None, "Caf\u{e9}"),
+        (b"Caf\xc3\xa9", Some("UTF-8"), "Caf\u{e9}"),
+        (b"Caf\xe9", Some("ISO-8859-1"), "Caf\u{e9}"),
+        (b"Caf\xe9", Some("latin1"), "Caf\u{e9}"),
+        (b"Caf\xe9", None, "Caf\u{fffd}"),
+    ];
+    for (bytes, encoding, decoded) in tests {
+        assert_eq!(decode_message(bytes, encoding), decoded.to_string());
+    }
+}
+
+fn commit_message(commit: &Commit) -> String {
+    decode_message(commit.message_bytes(), commit.message_encoding())
+}
+
+fn commit_summary(commit: &mut Commit) -> String {
+    let encoding = commit.message_encoding().map(String::from);
+    let summary = commit.summary_bytes().unwrap_or(b"");
+    decode_message(summary, encoding.as_ref().map(|e| &e[..]))
+}
+
+// Abbreviate an object id to a unique prefix of at least abbrev characters, the length from
+// --abbrev, or 0 to use just core.abbrev, which libgit2 reads itself.
+fn short_id(obj: &Object, abbrev: usize) -> Result<String> {
+    let short_id_buf = try!(obj.short_id());
+    let short_id = try!(short_id_buf.as_str().ok_or_else(|| format!("Internal error: short id of {} is not valid UTF-8", obj.id())));
+    if abbrev > short_id.len() {
+        let id = obj.id().to_string();
+        Ok(id[..std::cmp::min(abbrev, id.len())].to_string())
+    } else {
+        Ok(short_id.to_string())
+    }
+}
+
+fn commit_obj_summarize_components(commit: &mut Commit, abbrev: usize) -> Result<(String, String)> {
+    let short_id = try!(short_id(commit.as_object(), abbrev));
+    let summary = commit_summary(commit);
+    Ok((short_id, summary))
 }
 
-fn commit_summarize_components(repo: &Repository, id: Oid) -> Result<(String, String)> {
+fn commit_summarize_components(repo: &Repository, id: Oid, abbrev: usize) -> Result<(String, String)> {
     let mut commit = try!(repo.find_commit(id));
-    commit_obj_summarize_components(&mut commit)
+    commit_obj_summarize_components(&mut commit, abbrev)
 }
 
-fn commit_obj_summarize(commit: &mut Commit) -> Result<String> {
-    let (short_id, summary) = try!(commit_obj_summarize_components(commit));
+fn commit_obj_summarize(commit: &mut Commit, abbrev: usize) -> Result<String> {
+    let (short_id, summary) = try!(commit_obj_summarize_components(commit, abbrev));
     Ok(format!("{} {}", short_id, summary))
 }
 
-fn commit_summarize(repo: &Repository, id: Oid) -> Result<String> {
+fn commit_summarize(repo: &Repository, id: Oid, abbrev: usize) -> Result<String> {
     let mut commit = try!(repo.find_commit(id));
-    commit_obj_summarize(&mut commit)
+    commit_obj_summarize(&mut commit, abbrev)
+}
+
+fn config_multivar(config: &Config, name: &str) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    let entries = try!(config.entries(Some(&format!("^{}$", name.replace(".", "\\.")))));
+    for entry in &entries {
+        if let Some(value) = try!(entry).value() {
+            values.push(value.to_string());
+        }
+    }
+    Ok(values)
 }
 
 fn notfound_to_none<T>(result: std::result::Result<T, git2::Error>) -> Result<Option<T>> {
@@ -153,6 +257,28 @@ struct Internals<'repo> {
     working: TreeBuilder<'repo>,
 }
 
+// Check that a series name gives valid refs strictly within each of the series namespaces, so
+// that a name like "foo/../bar" can't refer to some other ref.
+fn check_series_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.split('/').all(|component| !component.is_empty() && !component.starts_with('.'))
+        && [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter().all(|prefix| git2::Reference::is_valid_name(&format!("{}{}", prefix, name)));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid series name \"{}\"", name).into())
+    }
+}
+
+#[test]
+fn test_check_series_name() {
+    assert!(check_series_name("feature").is_ok());
+    assert!(check_series_name("feature/foo-v2").is_ok());
+    for name in &["", "foo/../bar", "../foo", ".hidden", "foo/", "/foo", "foo//bar", "foo~1"] {
+        assert!(check_series_name(name).is_err(), "{:?} should be invalid", name);
+    }
+}
+
 impl<'repo> Internals<'repo> {
     fn read(repo: &'repo Repository) -> Result<Self> {
         let shead = try!(repo.find_reference(SHEAD_REF));
@@ -181,6 +307,7 @@ impl<'repo> Internals<'repo> {
     }
 
     fn exists(repo: &'repo Repository, series_name: &str) -> Result<bool> {
+        try!(check_series_name(series_name));
         for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
             let prefixed_name = format!("{}{}", prefix, series_name);
             if try!(notfound_to_none(repo.refname_to_id(&prefixed_name))).is_some() {
@@ -192,6 +319,8 @@ impl<'repo> Internals<'repo> {
 
     // Returns true if it had anything to copy.
     fn copy(repo: &'repo Repository, source: &str, dest: &str) -> Result<bool> {
+        try!(check_series_name(source));
+        try!(check_series_name(dest));
         let mut copied_any = false;
         for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
             let prefixed_source = format!("{}{}", prefix, source);
@@ -207,6 +336,7 @@ impl<'repo> Internals<'repo> {
 
     // Returns true if it had anything to delete.
     fn delete(repo: &'repo Repository, series_name: &str) -> Result<bool> {
+        try!(check_series_name(series_name));
         let mut deleted_any = false;
         for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
             let prefixed_name = format!("{}{}", prefix, series_name);
@@ -224,7 +354,7 @@ impl<'repo> Internals<'repo> {
         Ok(())
     }
 
-    fn write(&self, repo: &'repo Repository) -> Result<()> {
+    fn write(&self, repo: &'repo Repository, verbosity: Verbosity) -> Result<()> {
         let config = try!(repo.config());
         let author = try!(get_signature(&config, "AUTHOR"));
         let committer = try!(get_signature(&config, "COMMITTER"));
@@ -255,6 +385,9 @@ impl<'repo> Internals<'repo> {
             let commit_id = try!(repo.commit(None, &author, &committer, &refname, &tree, &parents_ref));
             try!(repo.reference_ensure_log(&refname));
             try!(reference_matching_opt(repo, &refname, commit_id, true, old_commit_id, &format!("commit: {}", refname)));
+            if verbosity >= Verbosity::Verbose {
+                println!("Updated {} to {}", refname, commit_id);
+            }
             Ok(())
         };
         try!(maybe_commit(STAGED_PREFIX, &self.staged));
@@ -263,26 +396,132 @@ impl<'repo> Internals<'repo> {
     }
 }
 
+// The changes that make up a series, as named by add, unadd, and commit.
+const SERIES_CHANGES: &'static [&'static str] = &["series", "base", "base-ref", "cover", "description", "config"];
+
+const SERIES_CONFIG_KEYS: &'static [&'static str] = &["cc", "reroll-count", "subject-prefix", "tag", "to", "url"];
+
+/// Settings attached to a series, stored as "key=value" lines in the "config" entry of the series
+/// tree. A key may appear more than once, for settings with multiple values such as "to".
+struct SeriesConfig {
+    entries: Vec<(String, String)>,
+}
+
+impl SeriesConfig {
+    fn parse(content: &str) -> Self {
+        let entries = content.lines().filter_map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap().trim().to_string();
+            let value = kv.next().unwrap_or("").trim().to_string();
+            Some((key, value))
+        }).collect();
+        SeriesConfig { entries: entries }
+    }
+
+    fn read(repo: &Repository, internals: &Internals) -> Result<Self> {
+        SeriesConfig::read_blob(repo, try!(internals.working.get("config")).map(|e| e.id()))
+    }
+
+    // Read the config committed in a series tree, such as the tree SHEAD points to.
+    fn read_tree(repo: &Repository, tree: &Tree) -> Result<Self> {
+        SeriesConfig::read_blob(repo, tree.get_name("config").map(|e| e.id()))
+    }
+
+    fn read_blob(repo: &Repository, config_id: Option<Oid>) -> Result<Self> {
+        match config_id {
+            None => Ok(SeriesConfig { entries: Vec::new() }),
+            Some(id) => Ok(SeriesConfig::parse(&String::from_utf8_lossy(try!(repo.find_blob(id)).content()))),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().rev().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v.as_str())
+    }
+
+    fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries.iter().filter(|&&(ref k, _)| k == key).map(|&(_, ref v)| v.as_str()).collect()
+    }
+
+    fn to_string(&self) -> String {
+        self.entries.iter().map(|&(ref k, ref v)| format!("{}={}\n", k, v)).collect()
+    }
+}
+
+#[test]
+fn test_series_config() {
+    let config = SeriesConfig::parse("# comment\nto = a@example.org\n\nsubject-prefix=PATCH foo\nto=b@example.org\nurl\n");
+    assert_eq!(config.get("subject-prefix"), Some("PATCH foo"));
+    assert_eq!(config.get("to"), Some("b@example.org"));
+    assert_eq!(config.get_all("to"), vec!["a@example.org", "b@example.org"]);
+    assert_eq!(config.get("url"), Some(""));
+    assert_eq!(config.get("cc"), None);
+    assert!(config.get_all("cc").is_empty());
+    assert_eq!(config.to_string(), "to=a@example.org\nsubject-prefix=PATCH foo\nto=b@example.org\nurl=\n");
+}
+
 fn diff_empty(diff: &Diff) -> bool {
     diff.deltas().len() == 0
 }
 
-fn add(repo: &Repository, m: &ArgMatches) -> Result<()> {
+fn add(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     let mut internals = try!(Internals::read(repo));
-    for file in m.values_of_os("change").unwrap() {
-        match try!(internals.working.get(file)) {
-            Some(entry) => { try!(internals.staged.insert(file, entry.id(), entry.filemode())); }
+    let changes: Vec<&str> = match m.values_of("change") {
+        Some(changes) => changes.collect(),
+        None => SERIES_CHANGES.to_vec(),
+    };
+    let stdin = std::io::stdin();
+    'changes: for change in changes {
+        let staged_id = try!(internals.staged.get(change)).map(|e| e.id());
+        let working = try!(internals.working.get(change)).map(|e| (e.id(), e.filemode()));
+        // With --patch, show each change that differs, and ask whether to add it, like "git add -p".
+        if m.is_present("patch") {
+            if staged_id == working.map(|(id, _)| id) {
+                continue;
+            }
+            println!("{}:", change);
+            if change == "series" || change == "base" {
+                if let Some(id) = staged_id {
+                    println!("-{}", try!(commit_summarize(repo, id, abbrev)));
+                }
+                if let Some((id, _)) = working {
+                    println!("+{}", try!(commit_summarize(repo, id, abbrev)));
+                }
+            } else {
+                try!(write_blob_diff(&mut std::io::stdout(), repo, change, staged_id, working.map(|(id, _)| id)));
+            }
+            loop {
+                print!("Add this change to {} [y,n,q,?]? ", change);
+                try!(std::io::stdout().flush());
+                let mut answer = String::new();
+                if try!(stdin.read_line(&mut answer)) == 0 {
+                    println!("");
+                    break 'changes;
+                }
+                match answer.trim() {
+                    "y" => break,
+                    "n" => continue 'changes,
+                    "q" => break 'changes,
+                    _ => println!("y - add this change\nn - do not add this change\nq - quit; do not add this change or any remaining ones"),
+                }
+            }
+        }
+        match working {
+            Some((id, filemode)) => { try!(internals.staged.insert(change, id, filemode)); }
             None => {
-                if try!(internals.staged.get(file)).is_some() {
-                    try!(internals.staged.remove(file));
+                if staged_id.is_some() {
+                    try!(internals.staged.remove(change));
                 }
             }
         }
     }
-    internals.write(repo)
+    internals.write(repo, verbosity)
 }
 
-fn unadd(repo: &Repository, m: &ArgMatches) -> Result<()> {
+fn unadd(repo: &Repository, m: &ArgMatches, verbosity: Verbosity) -> Result<()> {
     let shead = try!(repo.find_reference(SHEAD_REF));
     let started = {
         let shead_target = try!(shead.symbolic_target().ok_or("SHEAD not a symbolic reference"));
@@ -307,7 +546,7 @@ fn unadd(repo: &Repository, m: &ArgMatches) -> Result<()> {
             try!(internals.staged.remove(file))
         }
     }
-    internals.write(repo)
+    internals.write(repo, verbosity)
 }
 
 fn shead_series_name(shead: &Reference) -> Result<String> {
@@ -318,7 +557,63 @@ fn shead_series_name(shead: &Reference) -> Result<String> {
     Ok(shead_target[SERIES_PREFIX.len()..].to_string())
 }
 
-fn series(out: &mut Output, repo: &Repository) -> Result<()> {
+fn series_description(repo: &Repository, name: &str) -> Result<Option<String>> {
+    let internals = try!(Internals::read_series(repo, name));
+    let description_id = try!(internals.working.get("description")).map(|e| e.id());
+    match description_id {
+        None => Ok(None),
+        Some(id) => Ok(Some(String::from_utf8_lossy(try!(repo.find_blob(id)).content()).into_owned())),
+    }
+}
+
+// Match name against a shell-style glob pattern, where "*" matches any sequence of characters
+// (including "/") and "?" matches any single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last "*" in the pattern, and of the name when trying it.
+    let mut backtrack = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            backtrack = Some((star_p, star_n + 1));
+            p = star_p + 1;
+            n = star_n + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_glob_match() {
+    let tests = vec![
+        ("*", "anything", true),
+        ("*", "", true),
+        ("feature/*", "feature/foo", true),
+        ("feature/*", "feature/foo/bar", true),
+        ("feature/*", "bugfix/foo", false),
+        ("*fix*", "bugfix/foo", true),
+        ("v?", "v2", true),
+        ("v?", "v10", false),
+        ("a*b*c", "aXbYbZc", true),
+        ("a*b*c", "aXbYbZ", false),
+        ("exact", "exact", true),
+        ("exact", "exactly", false),
+    ];
+    for (pattern, name, result) in tests {
+        assert_eq!(glob_match(pattern, name), result, "glob_match({:?}, {:?})", pattern, name);
+    }
+}
+
+fn series(out: &mut Output, repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     let mut refs = Vec::new();
     for prefix in [SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX].iter() {
         let l = prefix.len();
@@ -334,12 +629,74 @@ fn series(out: &mut Output, repo: &Repository) -> Result<()> {
     refs.extend(shead_target.clone().into_iter());
     refs.sort();
     refs.dedup();
+    if let Some(pattern) = m.value_of("list") {
+        refs.retain(|name| glob_match(pattern, name));
+    }
+    if m.value_of("sort") == Some("updated") {
+        // Most recently updated first; the sort is stable, so series without a reflog stay in name
+        // order at the end.
+        let mut updated = Vec::new();
+        for name in refs.drain(..) {
+            let refname = format!("{}{}", SERIES_PREFIX, name);
+            let time = if try!(notfound_to_none(repo.refname_to_id(&refname))).is_some() {
+                try!(repo.reflog(&refname)).get(0).map(|entry| entry.committer().when().seconds())
+            } else {
+                None
+            };
+            updated.push((time, name));
+        }
+        updated.sort_by(|a, b| b.0.cmp(&a.0));
+        refs.extend(updated.into_iter().map(|(_, name)| name));
+    }
 
     let config = try!(try!(repo.config()).snapshot());
     try!(out.auto_pager(&config, "branch", false));
     let color_current = try!(out.get_color(&config, "branch", "current", "green"));
     let color_plain = try!(out.get_color(&config, "branch", "plain", "normal"));
+    let verbose = verbosity >= Verbosity::Verbose;
+    let mut names = Vec::new();
+    let mut patch_counts = Vec::new();
+    let mut covers = Vec::new();
     for name in refs.iter() {
+        let internals = if Some(name) == shead_target.as_ref() {
+            try!(Internals::read(repo))
+        } else {
+            try!(Internals::read_series(repo, name))
+        };
+        // Mark the current series with "+" if it has uncommitted changes.
+        let dirty = if Some(name) == shead_target.as_ref() {
+            let committed_tree_id = match try!(notfound_to_none(repo.refname_to_id(&format!("{}{}", SERIES_PREFIX, name)))) {
+                Some(id) => Some(try!(repo.find_commit(id)).tree_id()),
+                None => None,
+            };
+            Some(try!(internals.staged.write())) != committed_tree_id || Some(try!(internals.working.write())) != committed_tree_id
+        } else {
+            false
+        };
+        names.push(format!("{}{}", name, if dirty { "+" } else { "" }));
+        let series_id = try!(internals.working.get("series")).map(|e| e.id());
+        let base_id = try!(internals.working.get("base")).map(|e| e.id());
+        patch_counts.push(match (series_id, base_id) {
+            (Some(series_id), Some(base_id)) => {
+                let count = try!(series_commits(repo, series_id, base_id, true, abbrev)).len();
+                format!("({} patch{})", count, if count == 1 { "" } else { "es" })
+            }
+            _ => "(base unset)".to_string(),
+        });
+        if verbose {
+            covers.push(match try!(internals.working.get("cover")) {
+                None => "cover: none".to_string(),
+                Some(entry) => {
+                    let content = String::from_utf8_lossy(try!(repo.find_blob(entry.id())).content()).into_owned();
+                    let lines = content.lines().count();
+                    format!("cover: {} line{}", lines, if lines == 1 { "" } else { "s" })
+                }
+            });
+        }
+    }
+    let width = names.iter().zip(patch_counts.iter()).map(|(name, count)| name.len() + count.len()).max().unwrap_or(0);
+    let cover_width = covers.iter().map(|c| c.len()).max().unwrap_or(0);
+    for (n, ((name, display_name), patch_count)) in refs.iter().zip(names.iter()).zip(patch_counts.iter()).enumerate() {
         let (star, color) = if Some(name) == shead_target.as_ref() {
             ('*', color_current)
         } else {
@@ -350,15 +707,28 @@ fn series(out: &mut Output, repo: &Repository) -> Result<()> {
         } else {
             ""
         };
-        try!(writeln!(out, "{} {}{}", star, color.paint(name as &str), new));
+        if verbose {
+            let description = try!(series_description(repo, name));
+            let summary = description.as_ref().and_then(|d| d.lines().next()).unwrap_or("");
+            let padding: String = std::iter::repeat(' ').take(width - display_name.len() - patch_count.len()).collect();
+            let cover = &covers[n];
+            let cover_padding: String = std::iter::repeat(' ').take(cover_width - cover.len()).collect();
+            try!(writeln!(out, "{} {} {}{} {}{} {}{}", star, color.paint(display_name as &str), patch_count, padding, cover, cover_padding, summary, new));
+        } else {
+            try!(writeln!(out, "{} {} {}{}", star, color.paint(display_name as &str), patch_count, new));
+        }
     }
     if refs.is_empty() {
-        try!(writeln!(out, "No series; use \"git series start <name>\" to start"));
+        if let Some(pattern) = m.value_of("list") {
+            try!(writeln!(out, "No series matching \"{}\"", pattern));
+        } else {
+            try!(writeln!(out, "No series; use \"git series start <name>\" to start"));
+        }
     }
     Ok(())
 }
 
-fn start(repo: &Repository, m: &ArgMatches) -> Result<()> {
+fn start(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     let head = try!(repo.head());
     let head_commit = try!(peel_to_commit(head));
     let head_id = head_commit.as_object().id();
@@ -367,24 +737,69 @@ fn start(repo: &Repository, m: &ArgMatches) -> Result<()> {
     if try!(Internals::exists(repo, name)) {
         return Err(format!("Series {} already exists.\nUse checkout to resume working on an existing patch series.", name).into());
     }
-    let prefixed_name = &[SERIES_PREFIX, name].concat();
-    try!(repo.reference_symbolic(SHEAD_REF, &prefixed_name, true, &format!("git series start {}", name)));
+    start_series(repo, name, head_id, None, &format!("start {}", name), verbosity, abbrev)
+}
 
-    let internals = try!(Internals::read(repo));
-    try!(internals.write(repo));
+// Start the new series name at head_id, checking out head_id if HEAD doesn't already point there.
+fn start_series(repo: &Repository, name: &str, head_id: Oid, base_id: Option<Oid>, command: &str, verbosity: Verbosity, abbrev: usize) -> Result<()> {
+    let old_head_id = try!(peel_to_commit(try!(repo.head()))).id();
+    if old_head_id != head_id {
+        try!(checkout_tree(repo, &try!(repo.find_object(head_id, None)), false, verbosity));
+    }
+
+    let prefixed_name = &[SERIES_PREFIX, name].concat();
+    try!(repo.reference_symbolic(SHEAD_REF, &prefixed_name, true, &format!("git series {}", command)));
 
     // git status parses this reflog string; the prefix must remain "checkout: moving from ".
-    try!(repo.reference("HEAD", head_id, true, &format!("checkout: moving from {} to {} (git series start {})", head_id, head_id, name)));
-    println!("HEAD is now detached at {}", try!(commit_summarize(&repo, head_id)));
+    try!(repo.reference("HEAD", head_id, true, &format!("checkout: moving from {} to {} (git series {})", old_head_id, head_id, command)));
+
+    let mut internals = try!(Internals::read(repo));
+    if let Some(base_id) = base_id {
+        try!(internals.working.insert("base", base_id, GIT_FILEMODE_COMMIT as i32));
+    }
+    try!(internals.write(repo, verbosity));
+
+    if verbosity > Verbosity::Quiet {
+        println!("HEAD is now detached at {}", try!(commit_summarize(&repo, head_id, abbrev)));
+    }
     Ok(())
 }
 
-fn checkout_tree(repo: &Repository, treeish: &Object) -> Result<()> {
+fn import(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
+    let branch_name = m.value_of("branch").unwrap();
+    let name = m.value_of("name").unwrap_or(branch_name);
+    if try!(Internals::exists(repo, name)) {
+        return Err(format!("Series {} already exists.\nUse \"git series import --name <name>\" to import under another name.", name).into());
+    }
+
+    let branch = try!(repo.find_branch(branch_name, git2::BranchType::Local).map_err(|e| format!("Could not find branch {}: {}", branch_name, e)));
+    let branch_id = try!(peel_to_commit(try!(branch.get().resolve()))).id();
+    let base_id = match m.value_of("base") {
+        Some(base) => try!(try!(repo.revparse_single(base)).peel(ObjectType::Commit)).id(),
+        None => {
+            let upstream = try!(branch.upstream().map_err(|_| format!("Branch {} has no upstream to find a base from.\nUse \"git series import --base <base>\" to set the base.", branch_name)));
+            let upstream_id = try!(upstream.get().target().ok_or(format!("Upstream of branch {} is not a direct reference", branch_name)));
+            try!(repo.merge_base(branch_id, upstream_id))
+        }
+    };
+    if !try!(is_ancestor(repo, base_id, branch_id)) {
+        return Err(format!("Cannot import: base {} is not an ancestor of branch {}", base_id, branch_name).into());
+    }
+
+    start_series(repo, name, branch_id, Some(base_id), &format!("import {}", branch_name), verbosity, abbrev)
+}
+
+fn checkout_tree(repo: &Repository, treeish: &Object, force: bool, verbosity: Verbosity) -> Result<()> {
     let mut conflicts = Vec::new();
     let mut dirty = Vec::new();
+    let show_progress = verbosity > Verbosity::Quiet && isatty::stdout_isatty();
     let result = {
         let mut opts = git2::build::CheckoutBuilder::new();
-        opts.safe();
+        if force {
+            opts.force();
+        } else {
+            opts.safe();
+        }
         opts.notify_on(git2::CHECKOUT_NOTIFICATION_CONFLICT | git2::CHECKOUT_NOTIFICATION_DIRTY);
         opts.notify(|t, path, _, _, _| {
             let path = path.unwrap().to_owned();
@@ -395,7 +810,7 @@ fn checkout_tree(repo: &Repository, treeish: &Object) -> Result<()> {
             }
             true
         });
-        if isatty::stdout_isatty() {
+        if show_progress {
             opts.progress(|_, completed, total| {
                 let total = total.to_string();
                 print!("\rChecking out files: {1:0$}/{2}", total.len(), completed, total);
@@ -407,15 +822,18 @@ fn checkout_tree(repo: &Repository, treeish: &Object) -> Result<()> {
         Err(ref e) if e.code() == git2::ErrorCode::Conflict => {
             let mut msg = String::new();
             writeln!(msg, "error: Your changes to the following files would be overwritten by checkout:").unwrap();
-            for path in conflicts {
+            for path in conflicts.iter() {
                 writeln!(msg, "        {}", path.to_string_lossy()).unwrap();
             }
-            writeln!(msg, "Please, commit your changes or stash them before you switch series.").unwrap();
-            return Err(msg.into());
+            writeln!(msg, "{} file{} would be overwritten.", conflicts.len(), if conflicts.len() == 1 { "" } else { "s" }).unwrap();
+            writeln!(msg, "Please commit your changes, or stash them with \"git stash\", before you switch series.").unwrap();
+            return Err(Error::CheckoutConflict(msg));
         }
         _ => try!(result),
     }
-    println!("");
+    if show_progress {
+        println!("");
+    }
     if !dirty.is_empty() {
         let mut stderr = std::io::stderr();
         writeln!(stderr, "Files with changes unaffected by checkout:").unwrap();
@@ -426,7 +844,7 @@ fn checkout_tree(repo: &Repository, treeish: &Object) -> Result<()> {
     Ok(())
 }
 
-fn checkout(repo: &Repository, m: &ArgMatches) -> Result<()> {
+fn checkout(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     match repo.state() {
         git2::RepositoryState::Clean => (),
         s => { return Err(format!("{:?} in progress; cannot checkout patch series", s).into()); }
@@ -440,81 +858,420 @@ fn checkout(repo: &Repository, m: &ArgMatches) -> Result<()> {
     let new_head_id = try!(try!(internals.working.get("series")).ok_or(format!("Could not find \"series\" in \"{}\"", name))).id();
     let new_head = try!(repo.find_commit(new_head_id)).into_object();
 
-    try!(checkout_tree(repo, &new_head));
+    // Check the branch before checking out anything.
+    let branch = m.value_of("branch").or(m.value_of("reset-branch"));
+    let branch_ref = branch.map(|branch| format!("refs/heads/{}", branch));
+    if let Some(ref branch_ref) = branch_ref {
+        if !git2::Reference::is_valid_name(branch_ref) {
+            return Err(format!("Invalid branch name \"{}\"", &branch_ref["refs/heads/".len()..]).into());
+        }
+        if let Some(id) = try!(notfound_to_none(repo.refname_to_id(branch_ref))) {
+            if id != new_head_id && !m.is_present("reset-branch") {
+                return Err(format!("Branch {} already exists at {}; use \"git series checkout -B\" to reset it",
+                                   &branch_ref["refs/heads/".len()..], try!(commit_summarize(&repo, id, abbrev))).into());
+            }
+        }
+    }
+
+    match checkout_tree(repo, &new_head, m.is_present("force"), verbosity) {
+        Err(Error::CheckoutConflict(mut msg)) => {
+            writeln!(msg, "Or use \"git series checkout -f {}\" to discard them.", name).unwrap();
+            return Err(Error::CheckoutConflict(msg));
+        }
+        result => try!(result),
+    }
 
     let head = try!(repo.head());
     let head_commit = try!(peel_to_commit(head));
     let head_id = head_commit.as_object().id();
-    println!("Previous HEAD position was {}", try!(commit_summarize(&repo, head_id)));
+    if verbosity > Verbosity::Quiet {
+        println!("Previous HEAD position was {}", try!(commit_summarize(&repo, head_id, abbrev)));
+    }
 
     let prefixed_name = &[SERIES_PREFIX, name].concat();
     try!(repo.reference_symbolic(SHEAD_REF, &prefixed_name, true, &format!("git series checkout {}", name)));
-    try!(internals.write(repo));
-
-    // git status parses this reflog string; the prefix must remain "checkout: moving from ".
-    try!(repo.reference("HEAD", new_head_id, true, &format!("checkout: moving from {} to {} (git series checkout {})", head_id, new_head_id, name)));
-    println!("HEAD is now detached at {}", try!(commit_summarize(&repo, new_head_id)));
+    try!(internals.write(repo, verbosity));
+
+    // git status parses these reflog strings; the prefix must remain "checkout: moving from ".
+    match (branch, branch_ref) {
+        (Some(branch), Some(branch_ref)) => {
+            try!(repo.reference(&branch_ref, new_head_id, true, &format!("git series checkout {}", name)));
+            try!(repo.reference_symbolic("HEAD", &branch_ref, true, &format!("checkout: moving from {} to {} (git series checkout {})", head_id, branch, name)));
+            if verbosity > Verbosity::Quiet {
+                println!("Switched to branch {} at {}", branch, try!(commit_summarize(&repo, new_head_id, abbrev)));
+            }
+        }
+        _ => {
+            try!(repo.reference("HEAD", new_head_id, true, &format!("checkout: moving from {} to {} (git series checkout {})", head_id, new_head_id, name)));
+            if verbosity > Verbosity::Quiet {
+                println!("HEAD is now detached at {}", try!(commit_summarize(&repo, new_head_id, abbrev)));
+            }
+        }
+    }
 
     Ok(())
 }
 
-fn base(repo: &Repository, m: &ArgMatches) -> Result<()> {
+// Read the name of the ref a series tracks as its base, as set by "git series base --track".
+fn base_ref_name(repo: &Repository, entry: Option<TreeEntry>) -> Result<Option<String>> {
+    match entry {
+        None => Ok(None),
+        Some(entry) => {
+            let blob = try!(repo.find_blob(entry.id()));
+            Ok(Some(try!(std::str::from_utf8(blob.content())).trim().to_string()))
+        },
+    }
+}
+
+// Resolve the tracked base ref of a series, if any, to its current tip.
+fn tracked_base_tip(repo: &Repository, entry: Option<TreeEntry>) -> Result<Option<(String, Oid)>> {
+    let name = match try!(base_ref_name(repo, entry)) {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let obj = try!(repo.revparse_single(&name).map_err(|e| format!("Cannot resolve tracked base {}: {}", name, e)));
+    let tip = try!(obj.peel(ObjectType::Commit)).id();
+    Ok(Some((name, tip)))
+}
+
+// Find the point where a series forks from the ref it tracks as its base.
+fn fork_point(repo: &Repository, name: &str, tip: Oid, series_id: Oid) -> Result<Oid> {
+    Ok(try!(repo.merge_base(series_id, tip).map_err(|_| format!("Patch series has no common ancestor with tracked base {}", name))))
+}
+
+// Find the base to use for a committed series: the fork point from its tracked base ref if it
+// has one, or its fixed base commit otherwise.
+fn resolve_base(repo: &Repository, tree: &Tree, base_id: Oid, series_id: Oid) -> Result<Oid> {
+    match try!(tracked_base_tip(repo, tree.get_name("base-ref"))) {
+        Some((name, tip)) => fork_point(repo, &name, tip, series_id),
+        None => Ok(base_id),
+    }
+}
+
+fn base(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     let mut internals = try!(Internals::read(repo));
 
     let current_base_id = match try!(internals.working.get("base")) {
         Some(entry) => entry.id(),
         _ => zero_oid(),
     };
+    let current_base_ref = try!(base_ref_name(repo, try!(internals.working.get("base-ref"))));
 
-    if !m.is_present("delete") && !m.is_present("base") {
+    if !m.is_present("delete") && !m.is_present("base") && !m.is_present("track") {
         if current_base_id.is_zero() {
             return Err("Patch series has no base set".into());
         } else {
             println!("{}", current_base_id);
+            if verbosity > Verbosity::Normal {
+                if let Some(name) = current_base_ref {
+                    println!("Tracking {}", name);
+                }
+            }
             return Ok(());
         }
     }
 
-    let new_base_id = if m.is_present("delete") {
-        zero_oid()
+    let (new_base_id, new_base_ref) = if m.is_present("delete") {
+        (zero_oid(), None)
+    } else if let Some(name) = m.value_of("track") {
+        let obj = try!(repo.revparse_single(name));
+        let tip = try!(obj.peel(ObjectType::Commit)).id();
+        let s_working_series = try!(try!(internals.working.get("series")).ok_or("Could not find entry \"series\" in working vesion of current series"));
+        (try!(fork_point(repo, name, tip, s_working_series.id())), Some(name.to_string()))
     } else {
         let base = m.value_of("base").unwrap();
         let base_object = try!(repo.revparse_single(base));
         let base_commit = try!(base_object.peel(ObjectType::Commit));
         let base_id = base_commit.id();
         let s_working_series = try!(try!(internals.working.get("series")).ok_or("Could not find entry \"series\" in working vesion of current series"));
-        if base_id != s_working_series.id() && !try!(repo.graph_descendant_of(s_working_series.id(), base_id)) {
-            return Err(format!("Cannot set base to {}: not an ancestor of the patch series {}", base, s_working_series.id()).into());
+        if !try!(is_ancestor(repo, base_id, s_working_series.id())) {
+            if !m.is_present("force") {
+                return Err(format!("Cannot set base to {}: not an ancestor of the patch series {}\nUse \"git series base -f\" to set it anyway.", base, s_working_series.id()).into());
+            }
+            writeln!(std::io::stderr(), concat!(
+                     "Warning: base {} is not an ancestor of the patch series {}\n",
+                     "\"git series commit\" and \"git series format\" will refuse it until you rebase the series onto it,\n",
+                     "such as with \"git series rebase --onto {} <upstream>\"."),
+                     base, s_working_series.id(), base).unwrap();
         }
-        base_id
+        (base_id, None)
     };
 
-    if current_base_id == new_base_id {
-        println!("Base unchanged");
+    if current_base_id == new_base_id && current_base_ref == new_base_ref {
+        if verbosity > Verbosity::Quiet {
+            println!("Base unchanged");
+        }
         return Ok(());
     }
 
-    if !current_base_id.is_zero() {
-        println!("Previous base was {}", try!(commit_summarize(&repo, current_base_id)));
+    if !current_base_id.is_zero() && current_base_id != new_base_id {
+        if verbosity > Verbosity::Quiet {
+            println!("Previous base was {}", try!(commit_summarize(&repo, current_base_id, abbrev)));
+        }
+    }
+
+    match new_base_ref {
+        Some(ref name) => {
+            let blob = try!(repo.blob(format!("{}\n", name).as_bytes()));
+            try!(internals.working.insert("base-ref", blob, GIT_FILEMODE_BLOB as i32));
+        },
+        None => {
+            if current_base_ref.is_some() {
+                try!(internals.working.remove("base-ref"));
+            }
+        },
     }
 
     if new_base_id.is_zero() {
         try!(internals.working.remove("base"));
-        try!(internals.write(repo));
-        println!("Cleared patch series base");
+        try!(internals.write(repo, verbosity));
+        if verbosity > Verbosity::Quiet {
+            println!("Cleared patch series base");
+        }
     } else {
         try!(internals.working.insert("base", new_base_id, GIT_FILEMODE_COMMIT as i32));
-        try!(internals.write(repo));
-        println!("Set patch series base to {}", try!(commit_summarize(&repo, new_base_id)));
+        try!(internals.write(repo, verbosity));
+        if verbosity > Verbosity::Quiet {
+            println!("Set patch series base to {}", try!(commit_summarize(&repo, new_base_id, abbrev)));
+            if let Some(ref name) = new_base_ref {
+                println!("Tracking {}", name);
+            }
+        }
     }
 
     Ok(())
 }
 
-fn detach(repo: &Repository) -> Result<()> {
-    match repo.find_reference(SHEAD_REF) {
-        Ok(mut r) => try!(r.delete()),
+fn detach(repo: &Repository, m: &ArgMatches) -> Result<()> {
+    let mut shead = match repo.find_reference(SHEAD_REF) {
+        Ok(r) => r,
         Err(_) => { return Err("No current patch series to detach from.".into()); }
+    };
+
+    if !m.is_present("force") {
+        let series_name = try!(shead_series_name(&shead));
+        let internals = try!(Internals::read(repo));
+        let working_tree = try!(repo.find_tree(try!(internals.working.write())));
+        let staged_tree = try!(repo.find_tree(try!(internals.staged.write())));
+        let shead_tree = match try!(notfound_to_none(shead.resolve())) {
+            Some(r) => Some(try!(try!(peel_to_commit(r)).tree())),
+            None => None,
+        };
+
+        let mut left_behind = Vec::new();
+        let staged_diff = try!(repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&staged_tree), None));
+        let unstaged_diff = try!(repo.diff_tree_to_tree(Some(&staged_tree), Some(&working_tree), None));
+        for &(kind, ref diff) in [("staged", &staged_diff), ("unstaged", &unstaged_diff)].iter() {
+            for delta in diff.deltas() {
+                left_behind.push(format!("        {} {:?}:   {}", kind, delta.status(), delta.old_file().path().unwrap().to_string_lossy()));
+            }
+        }
+        if !left_behind.is_empty() {
+            return Err(format!("Series {} has uncommitted changes:\n{}\nUse \"git series commit\" to commit them, or \"git series detach --force\" to detach anyway.",
+                               series_name, left_behind.join("\n")).into());
+        }
+    }
+
+    try!(shead.delete());
+    Ok(())
+}
+
+// The state that git series undo restores: the series SHEAD names, the committed, staged, and
+// working versions of a series, and HEAD, which commands like checkout and rebase move along with
+// them.
+#[derive(PartialEq)]
+struct UndoState {
+    shead: Option<String>,
+    series: Option<Oid>,
+    staged: Option<Oid>,
+    working: Option<Oid>,
+    head_ref: Option<String>,
+    head: Option<Oid>,
+}
+
+// Read the current UndoState, with the versions of series_name, or of the current series if None.
+fn undo_state(repo: &Repository, series_name: Option<&str>) -> Result<UndoState> {
+    let shead = match try!(notfound_to_none(repo.find_reference(SHEAD_REF))) {
+        Some(shead) => Some(try!(shead_series_name(&shead))),
+        None => None,
+    };
+    let name = match series_name {
+        Some(name) => Some(name.to_string()),
+        None => shead.clone(),
+    };
+    let id = |prefix: &str| -> Result<Option<Oid>> {
+        match name {
+            Some(ref name) => notfound_to_none(repo.refname_to_id(&format!("{}{}", prefix, name))),
+            None => Ok(None),
+        }
+    };
+    let head_ref = try!(repo.find_reference("HEAD")).symbolic_target().map(|s| s.to_string());
+    Ok(UndoState {
+        series: try!(id(SERIES_PREFIX)),
+        staged: try!(id(STAGED_PREFIX)),
+        working: try!(id(WORKING_PREFIX)),
+        shead: shead,
+        head_ref: head_ref,
+        head: try!(notfound_to_none(repo.refname_to_id("HEAD"))),
+    })
+}
+
+// Write the record of the state before a command, for record_undo to add to UNDO_REF if the command
+// changes it. This runs before the command changes anything, and only writes objects. Each record
+// is a commit whose tree holds the name of the series in "shead", its versions as "series",
+// "staged", and "working", the commit at HEAD as "head", and the branch HEAD was on, if any, as
+// "head-ref"; its first parent is the previous record, and its other parents keep those commits
+// reachable.
+fn undo_record(repo: &Repository, before: &UndoState, command: &str) -> Result<Oid> {
+    let mut tb = try!(repo.treebuilder(None));
+    if let Some(ref shead) = before.shead {
+        try!(tb.insert("shead", try!(repo.blob(shead.as_bytes())), GIT_FILEMODE_BLOB as i32));
+    }
+    if let Some(ref head_ref) = before.head_ref {
+        try!(tb.insert("head-ref", try!(repo.blob(head_ref.as_bytes())), GIT_FILEMODE_BLOB as i32));
+    }
+    let mut parents = Vec::new();
+    let previous_id = try!(notfound_to_none(repo.refname_to_id(UNDO_REF)));
+    parents.extend(previous_id);
+    for &(name, id) in [("series", before.series), ("staged", before.staged), ("working", before.working), ("head", before.head)].iter() {
+        if let Some(id) = id {
+            try!(tb.insert(name, id, GIT_FILEMODE_COMMIT as i32));
+            if !parents.contains(&id) {
+                parents.push(id);
+            }
+        }
+    }
+    let tree = try!(repo.find_tree(try!(tb.write())));
+    let parents: Vec<Commit> = try!(parents.into_iter().map(|id| repo.find_commit(id)).collect());
+    let parents_ref: Vec<&_> = parents.iter().collect();
+    // Nobody reads the identity of a record, so don't fail a command for the lack of one.
+    let config = try!(repo.config());
+    let fallback = || git2::Signature::now("git-series", "git-series@localhost");
+    let author = try!(get_signature(&config, "AUTHOR").or_else(|_| fallback()));
+    let committer = try!(get_signature(&config, "COMMITTER").or_else(|_| fallback()));
+    Ok(try!(repo.commit(None, &author, &committer, command, &tree, &parents_ref)))
+}
+
+// If command changed the state from before, add record_id, the record of before from undo_record,
+// to UNDO_REF for git series undo.
+fn record_undo(repo: &Repository, before: &UndoState, record_id: Oid, command: &str) -> Result<()> {
+    if try!(undo_state(repo, before.shead.as_ref().map(|s| &s[..]))) == *before {
+        return Ok(());
+    }
+    let previous_id = try!(notfound_to_none(repo.refname_to_id(UNDO_REF)));
+    try!(repo.reference_ensure_log(UNDO_REF));
+    try!(reference_matching_opt(repo, UNDO_REF, record_id, true, previous_id, command));
+    Ok(())
+}
+
+// Undo the most recent change, by restoring SHEAD, the versions of the series it named, and HEAD
+// to the state recorded in UNDO_REF before that change. Undo records the state it replaces like any
+// other change, so a second undo restores the undone change. This never deletes the ref of a
+// version, or its reflog: a staged or working version that didn't exist gets a commit matching the
+// committed version, which means the same thing.
+fn undo(repo: &Repository, verbosity: Verbosity) -> Result<()> {
+    match repo.state() {
+        git2::RepositoryState::Clean => (),
+        s => { return Err(format!("{:?} in progress; cannot undo", s).into()); }
+    }
+    let record_id = try!(try!(notfound_to_none(repo.refname_to_id(UNDO_REF))).ok_or("Nothing to undo"));
+    let record = try!(repo.find_commit(record_id));
+    let command = String::from_utf8_lossy(record.message_bytes()).into_owned();
+    let message = format!("git series undo: {}", command);
+    let tree = try!(record.tree());
+    let entry_id = |name: &str| tree.get_name(name).map(|e| e.id());
+    let entry_string = |name: &str| -> Result<Option<String>> {
+        match entry_id(name) {
+            Some(id) => Ok(Some(String::from_utf8_lossy(try!(repo.find_blob(id)).content()).into_owned())),
+            None => Ok(None),
+        }
+    };
+    let shead = try!(entry_string("shead"));
+    let series_id = entry_id("series");
+
+    // Check before changing anything, so undo never stops halfway.
+    if let Some(ref name) = shead {
+        if series_id.is_none() && try!(notfound_to_none(repo.refname_to_id(&format!("{}{}", SERIES_PREFIX, name)))).is_some() {
+            return Err(format!("Cannot undo \"{}\": series {} had no commits before it, and undo never deletes a series commit", command, name).into());
+        }
+    }
+    let config = try!(repo.config());
+    let author = try!(get_signature(&config, "AUTHOR"));
+    let committer = try!(get_signature(&config, "COMMITTER"));
+
+    // The working version of the series follows HEAD, so restore HEAD along with it. Check out its
+    // tree before changing any ref; this fails without changing anything if the checkout would
+    // overwrite local changes.
+    let head_ref = try!(entry_string("head-ref"));
+    let head_id = entry_id("head");
+    let current_head_ref = try!(repo.find_reference("HEAD")).symbolic_target().map(|s| s.to_string());
+    let current_head_id = try!(notfound_to_none(repo.refname_to_id("HEAD")));
+    let restore_head = match head_id {
+        Some(id) if (&head_ref, Some(id)) != (&current_head_ref, current_head_id) => Some(id),
+        _ => None,
+    };
+    if let Some(id) = restore_head {
+        if let Some(ref head_ref) = head_ref {
+            if try!(notfound_to_none(repo.refname_to_id(head_ref))) != Some(id) {
+                return Err(format!("Cannot undo \"{}\": {} no longer points to {}, where HEAD was before it", command, head_ref, id).into());
+            }
+        }
+        let head_commit = try!(repo.find_commit(id)).into_object();
+        match checkout_tree(repo, &head_commit, false, verbosity) {
+            Err(Error::CheckoutConflict(mut msg)) => {
+                writeln!(msg, "Cannot undo \"{}\" without restoring HEAD to {}.", command, id).unwrap();
+                return Err(Error::CheckoutConflict(msg));
+            }
+            result => try!(result),
+        }
+    }
+
+    match shead {
+        Some(name) => {
+            let series_ref = format!("{}{}", SERIES_PREFIX, name);
+            for &(prefix, key) in [(STAGED_PREFIX, "staged"), (WORKING_PREFIX, "working")].iter() {
+                let refname = format!("{}{}", prefix, name);
+                let id = match entry_id(key) {
+                    Some(id) => id,
+                    None => {
+                        if try!(notfound_to_none(repo.refname_to_id(&refname))).is_none() {
+                            continue;
+                        }
+                        let (committed_tree, parents) = match series_id {
+                            Some(id) => {
+                                let commit = try!(repo.find_commit(id));
+                                (try!(commit.tree()), vec![commit])
+                            }
+                            None => (try!(repo.find_tree(try!(try!(repo.treebuilder(None)).write()))), Vec::new()),
+                        };
+                        let parents_ref: Vec<&_> = parents.iter().collect();
+                        try!(repo.commit(None, &author, &committer, &refname, &committed_tree, &parents_ref))
+                    }
+                };
+                try!(repo.reference_ensure_log(&refname));
+                try!(repo.reference(&refname, id, true, &message));
+            }
+            if let Some(id) = series_id {
+                try!(repo.reference_ensure_log(&series_ref));
+                try!(repo.reference(&series_ref, id, true, &message));
+            }
+            try!(repo.reference_symbolic(SHEAD_REF, &series_ref, true, &message));
+        }
+        None => {
+            // No series was current; detach, as git series detach does.
+            if let Some(mut shead) = try!(notfound_to_none(repo.find_reference(SHEAD_REF))) {
+                try!(shead.delete());
+            }
+        }
+    }
+    if let Some(id) = restore_head {
+        match head_ref {
+            Some(ref head_ref) => { try!(repo.reference_symbolic("HEAD", head_ref, true, &message)); }
+            None => { try!(repo.reference("HEAD", id, true, &message)); }
+        }
+    }
+    if verbosity > Verbosity::Quiet {
+        println!("Undid \"{}\"", command);
     }
     Ok(())
 }
@@ -533,19 +1290,156 @@ fn delete(repo: &Repository, m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn do_diff(out: &mut Output, repo: &Repository) -> Result<()> {
-    let internals = try!(Internals::read(&repo));
-    let config = try!(try!(repo.config()).snapshot());
-    try!(out.auto_pager(&config, "diff", true));
-    let diffcolors = try!(DiffColors::new(out, &config));
-
+// The staged and working refs of series other than the current one that have no committed version.
+// Unless uncommitted, only include those holding nothing, as left behind by an interrupted
+// operation; a series started but never committed still counts as a series.
+fn prunable_refs(repo: &Repository, uncommitted: bool) -> Result<Vec<String>> {
+    let shead_target = if let Some(shead) = try!(notfound_to_none(repo.find_reference(SHEAD_REF))) {
+        Some(try!(shead_series_name(&shead)))
+    } else {
+        None
+    };
+    let mut names = Vec::new();
+    for prefix in [STAGED_PREFIX, WORKING_PREFIX].iter() {
+        let l = prefix.len();
+        for r in try!(repo.references_glob(&[prefix, "*"].concat())).names() {
+            names.push(try!(r)[l..].to_string());
+        }
+    }
+    names.sort();
+    names.dedup();
+    let mut stale = Vec::new();
+    for name in names {
+        if shead_target.as_ref() == Some(&name) {
+            continue;
+        }
+        if try!(notfound_to_none(repo.refname_to_id(&format!("{}{}", SERIES_PREFIX, name)))).is_some() {
+            continue;
+        }
+        let mut refs = Vec::new();
+        let mut empty = true;
+        for prefix in [STAGED_PREFIX, WORKING_PREFIX].iter() {
+            let refname = format!("{}{}", prefix, name);
+            if let Some(id) = try!(notfound_to_none(repo.refname_to_id(&refname))) {
+                empty = empty && try!(try!(repo.find_commit(id)).tree()).len() == 0;
+                refs.push(refname);
+            }
+        }
+        if empty || uncommitted {
+            stale.extend(refs);
+        }
+    }
+    Ok(stale)
+}
+
+#[test]
+fn test_prunable_refs() {
+    let dir = TempDir::new("git-series-test").unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    let sig = git2::Signature::now("Test", "test@example.com").unwrap();
+    let empty_tree = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+    let head_id = repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &empty_tree, &[]).unwrap();
+    // A series started but never committed, and not the current series.
+    let mut started = repo.treebuilder(None).unwrap();
+    started.insert("series", head_id, GIT_FILEMODE_COMMIT as i32).unwrap();
+    let started_tree = repo.find_tree(started.write().unwrap()).unwrap();
+    let started_id = repo.commit(None, &sig, &sig, "working", &started_tree, &[]).unwrap();
+    repo.reference(&format!("{}started", WORKING_PREFIX), started_id, false, "test").unwrap();
+    // Empty internals left behind, with nothing in them.
+    let empty_id = repo.commit(None, &sig, &sig, "staged", &empty_tree, &[]).unwrap();
+    repo.reference(&format!("{}leftover", STAGED_PREFIX), empty_id, false, "test").unwrap();
+
+    assert_eq!(prunable_refs(&repo, false).unwrap(), vec![format!("{}leftover", STAGED_PREFIX)]);
+    assert_eq!(prunable_refs(&repo, true).unwrap(), vec![format!("{}leftover", STAGED_PREFIX), format!("{}started", WORKING_PREFIX)]);
+}
+
+// Delete the staged and working versions of series that have no committed version, other than the
+// current series.
+fn prune(repo: &Repository, m: &ArgMatches, verbosity: Verbosity) -> Result<()> {
+    let dry_run = m.is_present("dry-run");
+    let stale = try!(prunable_refs(repo, m.is_present("uncommitted")));
+    for refname in stale {
+        if dry_run {
+            println!("Would prune {}", refname);
+            continue;
+        }
+        try!(try!(repo.find_reference(&refname)).delete());
+        if verbosity > Verbosity::Quiet {
+            println!("Pruned {}", refname);
+        }
+    }
+    Ok(())
+}
+
+// The tip of the working version of a series, including any new commits on HEAD for the current
+// series.
+fn series_tip(repo: &Repository, series_name: &str) -> Result<Oid> {
+    if !try!(Internals::exists(repo, series_name)) {
+        return Err(format!("Series {} does not exist.", series_name).into());
+    }
+    let shead_series = match try!(notfound_to_none(repo.find_reference(SHEAD_REF))) {
+        Some(shead) => Some(try!(shead_series_name(&shead))),
+        None => None,
+    };
+    let internals = if shead_series.as_ref().map(|s| s.as_str()) == Some(series_name) {
+        try!(Internals::read(repo))
+    } else {
+        try!(Internals::read_series(repo, series_name))
+    };
+    let series_id = try!(internals.working.get("series")).map(|e| e.id());
+    Ok(try!(series_id.ok_or(format!("Series {} has no series commit.", series_name))))
+}
+
+fn series_tip_tree<'repo>(repo: &'repo Repository, series_name: &str) -> Result<Tree<'repo>> {
+    let series_id = try!(series_tip(repo, series_name));
+    Ok(try!(try!(repo.find_commit(series_id)).tree()))
+}
+
+fn export(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
+    let name = m.value_of("name").unwrap();
+    let branch = m.value_of("branch").unwrap();
+    let refname = format!("refs/heads/{}", branch);
+    if !Reference::is_valid_name(&refname) {
+        return Err(format!("\"{}\" is not a valid branch name", branch).into());
+    }
+    let force = m.is_present("force");
+    if !force && try!(notfound_to_none(repo.refname_to_id(&refname))).is_some() {
+        return Err(format!("A branch named \"{}\" already exists.\nUse \"git series export --force\" to overwrite it.", branch).into());
+    }
+    let series_id = try!(series_tip(repo, name));
+    try!(repo.reference(&refname, series_id, force, &format!("git series export {} {}", name, branch)));
+    if verbosity > Verbosity::Quiet {
+        println!("Exported series {} to branch {} at {}", name, branch, try!(commit_summarize(repo, series_id, abbrev)));
+    }
+    Ok(())
+}
+
+fn do_diff(out: &mut Output, repo: &Repository, m: &ArgMatches, abbrev: usize) -> Result<()> {
+    let config = try!(try!(repo.config()).snapshot());
+    if let Some(mut names) = m.values_of("series") {
+        let tree1 = try!(series_tip_tree(repo, names.next().unwrap()));
+        let tree2 = try!(series_tip_tree(repo, names.next().unwrap()));
+        try!(out.auto_pager(&config, "diff", true));
+        let diffcolors = try!(DiffColors::new(out, &config));
+        let diff = try!(diff_trees(repo, Some(&tree1), Some(&tree2)));
+        try!(write_diff(out, &diffcolors, &diff, false));
+        return Ok(());
+    }
+
+    let internals = try!(Internals::read(&repo));
+    try!(out.auto_pager(&config, "diff", true));
+    let diffcolors = try!(DiffColors::new(out, &config));
+
     let working_tree = try!(repo.find_tree(try!(internals.working.write())));
     let staged_tree = try!(repo.find_tree(try!(internals.staged.write())));
 
-    write_series_diff(out, repo, &diffcolors, Some(&staged_tree), Some(&working_tree))
+    write_series_diff(out, repo, &diffcolors, Some(&staged_tree), Some(&working_tree), abbrev)
 }
 
 fn get_editor(config: &Config) -> Result<OsString> {
+    if let Some(e) = env::var_os("GIT_SERIES_EDITOR") {
+        return Ok(e);
+    }
     if let Some(e) = env::var_os("GIT_EDITOR") {
         return Ok(e);
     }
@@ -565,7 +1459,8 @@ fn get_editor(config: &Config) -> Result<OsString> {
         return Ok(e);
     }
     if terminal_is_dumb {
-        return Err("TERM unset or \"dumb\" but EDITOR unset".into());
+        return Err(concat!("No editor found; checked $GIT_SERIES_EDITOR, $GIT_EDITOR, core.editor in git config, and $EDITOR\n",
+                           "($VISUAL and the default vi are not used because TERM is unset or \"dumb\")").into());
     }
     return Ok("vi".into());
 }
@@ -632,6 +1527,21 @@ fn run_editor<S: AsRef<OsStr>>(config: &Config, filename: S) -> Result<()> {
     Ok(())
 }
 
+// Read back a file written by the editor, converting CRLF line endings to LF. If the file had any
+// CRLF line endings, rewrite it with the normalized contents, for the benefit of anything else
+// that reads it.
+fn read_edited_file<P: AsRef<Path>>(filename: P) -> Result<String> {
+    let mut bytes = Vec::new();
+    try!(try!(File::open(&filename)).read_to_end(&mut bytes));
+    let mut contents = try!(String::from_utf8(bytes).map_err(|e|
+            format!("{} is not valid UTF-8: {}", filename.as_ref().display(), e.utf8_error())));
+    if contents.contains("\r\n") {
+        contents = contents.replace("\r\n", "\n");
+        try!(try!(File::create(&filename)).write_all(contents.as_bytes()));
+    }
+    Ok(contents)
+}
+
 struct Output {
     pager: Option<std::process::Child>,
     include_stderr: bool,
@@ -739,13 +1649,58 @@ fn get_signature(config: &Config, which: &str) -> Result<git2::Signature<'static
     Ok(try!(git2::Signature::now(&name, &email)))
 }
 
-fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status: bool) -> Result<()> {
+// Parse an identity given on the command line, in the form "Name <email>".
+fn parse_ident(ident: &str) -> Result<(String, String)> {
+    let invalid = || format!("Invalid identity \"{}\"; expected \"Name <email>\"", ident);
+    let ident = ident.trim();
+    let lt = try!(ident.find('<').ok_or_else(&invalid));
+    let name = ident[..lt].trim();
+    let email = &ident[lt + 1..];
+    if name.is_empty() || !email.ends_with('>') {
+        return Err(invalid().into());
+    }
+    let email = &email[..email.len() - 1];
+    if email.is_empty() || email.contains('<') || email.contains('>') {
+        return Err(invalid().into());
+    }
+    Ok((name.to_string(), email.to_string()))
+}
+
+#[test]
+fn test_parse_ident() {
+    assert_eq!(parse_ident("A U Thor <author@example.com>").unwrap(), ("A U Thor".to_string(), "author@example.com".to_string()));
+    assert_eq!(parse_ident("  Someone   <s@example.com> ").unwrap(), ("Someone".to_string(), "s@example.com".to_string()));
+    for ident in &["", "author@example.com", "<author@example.com>", "A U Thor <>", "A U Thor <author@example.com", "A <b> <c>"] {
+        assert!(parse_ident(ident).is_err(), "{:?} should be invalid", ident);
+    }
+}
+
+fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status: bool, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     let config = try!(try!(repo.config()).snapshot());
-    let shead = match repo.find_reference(SHEAD_REF) {
-        Err(ref e) if e.code() == git2::ErrorCode::NotFound => { println!("No series; use \"git series start <name>\" to start"); return Ok(()); }
-        result => try!(result),
+    let current_name = match repo.find_reference(SHEAD_REF) {
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => None,
+        result => Some(try!(shead_series_name(&try!(result)))),
+    };
+    // "git series status <name>" can show a series other than the current one. Its working version
+    // doesn't follow HEAD, so only show what it has staged.
+    let other_name = match (m.value_of("name"), current_name.as_ref()) {
+        (Some(name), Some(current)) if name == current => None,
+        (name, _) => name,
+    };
+    let series_name = match (other_name, current_name) {
+        (Some(name), _) => {
+            if !try!(Internals::exists(repo, name)) {
+                return Err(format!("Series {} does not exist.", name).into());
+            }
+            name.to_string()
+        }
+        (None, Some(name)) => name,
+        (None, None) => { println!("No series; use \"git series start <name>\" to start"); return Ok(()); }
+    };
+    let series_ref = match other_name {
+        Some(name) => format!("{}{}", SERIES_PREFIX, name),
+        None => SHEAD_REF.to_string(),
     };
-    let series_name = try!(shead_series_name(&shead));
 
     if do_status {
         try!(out.auto_pager(&config, "status", false));
@@ -777,7 +1732,7 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
                 status.push(color_normal.paint("\n"));
             }
             status.push(color_normal.paint("        "));
-            status.push(color.paint(format!("{:?}:   {}\n", delta.status(), delta.old_file().path().unwrap().to_str().unwrap())));
+            status.push(color.paint(format!("{:?}:   {}\n", delta.status(), delta.old_file().path().unwrap().to_string_lossy())));
             true
         }, None, None, None));
 
@@ -789,13 +1744,37 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
     };
 
     let mut status = Vec::new();
-    status.push(color_header.paint(format!("On series {}\n", series_name)));
+    if other_name.is_some() {
+        status.push(color_header.paint(format!("Series {} (not the current series)\n", series_name)));
+    } else {
+        status.push(color_header.paint(format!("On series {}\n", series_name)));
+        if do_status && series_rebase_in_progress(repo) {
+            status.push(color_changed.paint("rebase in progress; run \"git rebase --continue\" or \"git rebase --abort\"\n"));
+        }
+    }
 
-    let mut internals = try!(Internals::read(repo));
+    let mut internals = if other_name.is_some() {
+        try!(Internals::read_series(repo, &series_name))
+    } else {
+        try!(Internals::read(repo))
+    };
     let working_tree = try!(repo.find_tree(try!(internals.working.write())));
     let staged_tree = try!(repo.find_tree(try!(internals.staged.write())));
 
-    let shead_commit = match shead.resolve() {
+    if do_status {
+        if let (Some(series), Some(base)) = (working_tree.get_name("series"), working_tree.get_name("base")) {
+            let (base_short_id, base_summary) = try!(commit_summarize_components(&repo, base.id(), abbrev));
+            if try!(is_ancestor(repo, base.id(), series.id())) {
+                let count = try!(series_commits(repo, series.id(), base.id(), true, abbrev)).len();
+                status.push(color_normal.paint(format!("Series is {} patch{} ahead of base {} ({})\n",
+                                                       count, if count == 1 { "" } else { "es" }, base_short_id, base_summary)));
+            } else {
+                status.push(color_changed.paint(format!("Warning: base {} ({}) is not an ancestor of the series\n", base_short_id, base_summary)));
+            }
+        }
+    }
+
+    let shead_commit = match repo.find_reference(&series_ref).and_then(|r| r.resolve()) {
         Ok(r) => Some(try!(peel_to_commit(r))),
         Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
             status.push(color_header.paint("\nInitial series commit\n"));
@@ -808,7 +1787,27 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
         None => None,
     };
 
+    // The working version of the series always follows HEAD, so new commits on HEAD show up as a
+    // change to "series"; explain that.
+    if do_status && other_name.is_none() {
+        let working_series = working_tree.get_name("series").map(|e| e.id());
+        let staged_series = staged_tree.get_name("series").map(|e| e.id());
+        let committed_series = shead_tree.as_ref().and_then(|t| t.get_name("series")).map(|e| e.id());
+        if let (Some(head_id), Some(committed_id)) = (working_series, committed_series) {
+            if head_id != committed_id && staged_series != working_series {
+                let (head_short, _) = try!(commit_summarize_components(&repo, head_id, abbrev));
+                let (committed_short, _) = try!(commit_summarize_components(&repo, committed_id, abbrev));
+                let how = if try!(repo.graph_descendant_of(head_id, committed_id)) { "has new commits since" } else { "differs from" };
+                status.push(color_header.paint(format!("HEAD ({}) {} the series last committed ({})\n", head_short, how, committed_short)));
+                status.push(color_header.paint("  (use \"git series add series\" to include HEAD in the next series commit)\n"));
+            }
+        }
+    }
+
     let commit_all = m.is_present("all");
+    // Named changes commit exactly those changes from the working version, on top of the last
+    // series commit, leaving anything else added for a later commit.
+    let commit_only: Option<Vec<&str>> = m.values_of("change").map(|v| v.collect());
 
     let (changes, tree) = if commit_all {
         let diff = try!(repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&working_tree), None));
@@ -817,6 +1816,32 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
             status.push(color_normal.paint("nothing to commit; series unchanged\n"));
         }
         (changes, working_tree)
+    } else if let Some(ref names) = commit_only {
+        let mut only = try!(repo.treebuilder(shead_tree.as_ref()));
+        for name in names {
+            match working_tree.get_name(name) {
+                Some(entry) => { try!(only.insert(name, entry.id(), entry.filemode())); }
+                None => {
+                    if try!(only.get(name)).is_some() {
+                        try!(only.remove(name));
+                    }
+                }
+            }
+        }
+        let only_tree = try!(repo.find_tree(try!(only.write())));
+        let diff = try!(repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&only_tree), None));
+        let changes = try!(write_status(&mut status, &diff, "Changes to be committed:", &color_normal, false, &[]));
+        if !changes {
+            status.push(color_normal.paint("nothing to commit; series unchanged\n"));
+        }
+        (changes, only_tree)
+    } else if other_name.is_some() {
+        let diff = try!(repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&staged_tree), None));
+        let changes = try!(write_status(&mut status, &diff, "Changes to be committed:", &color_updated, false, &[]));
+        if !changes {
+            status.push(color_normal.paint("nothing to commit; series unchanged\n"));
+        }
+        (changes, staged_tree)
     } else {
         let diff = try!(repo.diff_tree_to_tree(shead_tree.as_ref(), Some(&staged_tree), None));
         let changes_to_be_committed = try!(write_status(&mut status, &diff,
@@ -845,7 +1870,7 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
         if do_status {
             try!(write!(out, "{}", status));
         } else {
-            return Err(status.into());
+            return Err(Error::CommitNoChanges(status));
         }
         return Ok(());
     }
@@ -859,9 +1884,9 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
 
     // Check that the base is still an ancestor of the series
     if let Some(base) = tree.get_name("base") {
-        if base.id() != series_id && !try!(repo.graph_descendant_of(series_id, base.id())) {
-            let (base_short_id, base_summary) = try!(commit_summarize_components(&repo, base.id()));
-            let (series_short_id, series_summary) = try!(commit_summarize_components(&repo, series_id));
+        if !try!(is_ancestor(repo, base.id(), series_id)) {
+            let (base_short_id, base_summary) = try!(commit_summarize_components(&repo, base.id(), abbrev));
+            let (series_short_id, series_summary) = try!(commit_summarize_components(&repo, series_id, abbrev));
             return Err(format!(concat!(
                        "Cannot commit: base {} is not an ancestor of patch series {}\n",
                        "base   {} {}\n",
@@ -872,6 +1897,29 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
         }
     }
 
+    let author = match m.value_of("author") {
+        Some(ident) => {
+            let (name, email) = try!(parse_ident(ident).map_err(|e| format!("{} for --author", e)));
+            try!(git2::Signature::now(&name, &email))
+        }
+        None => try!(get_signature(&config, "AUTHOR")),
+    };
+
+    if m.is_present("dry-run") {
+        if let Some(msg) = m.value_of("m") {
+            if try!(git2::message_prettify(msg, git2::DEFAULT_COMMENT_CHAR)).is_empty() {
+                return Err("Aborting series commit due to empty commit message.".into());
+            }
+        }
+        try!(write!(out, "{}", status));
+        return Ok(());
+    }
+
+    let verify = !m.is_present("no-verify");
+    if verify {
+        try!(run_hook(repo, &config, "series-pre-commit", &[]));
+    }
+
     let msg = match m.value_of("m") {
         Some(s) => s.to_string(),
         None => {
@@ -887,13 +1935,12 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
             }
             if m.is_present("verbose") {
                 try!(writeln!(file, "{}\n{}", SCISSOR_LINE, SCISSOR_COMMENT));
-                try!(write_series_diff(&mut file, repo, &DiffColors::plain(), shead_tree.as_ref(), Some(&tree)));
+                try!(write_cover_diff(&mut file, repo, shead_tree.as_ref(), Some(&tree)));
+                try!(write_series_diff(&mut file, repo, &DiffColors::plain(), shead_tree.as_ref(), Some(&tree), abbrev));
             }
             drop(file);
             try!(run_editor(&config, &filename));
-            let mut file = try!(File::open(&filename));
-            let mut msg = String::new();
-            try!(file.read_to_string(&mut msg));
+            let mut msg = try!(read_edited_file(&filename));
             if let Some(scissor_index) = msg.find(SCISSOR_LINE) {
                 msg.truncate(scissor_index);
             }
@@ -903,8 +1950,20 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
     if msg.is_empty() {
         return Err("Aborting series commit due to empty commit message.".into());
     }
+    // Like the commit-msg hook of git commit, series-commit-msg can check or edit the message.
+    let msg = if verify && try!(find_hook(repo, &config, "series-commit-msg")).is_some() {
+        let filename = repo.path().join("SCOMMIT_EDITMSG");
+        try!(try!(File::create(&filename)).write_all(msg.as_bytes()));
+        try!(run_hook(repo, &config, "series-commit-msg", &[filename.as_os_str()]));
+        let msg = try!(git2::message_prettify(try!(read_edited_file(&filename)), git2::DEFAULT_COMMENT_CHAR));
+        if msg.is_empty() {
+            return Err("Aborting series commit due to empty commit message.".into());
+        }
+        msg
+    } else {
+        msg
+    };
 
-    let author = try!(get_signature(&config, "AUTHOR"));
     let committer = try!(get_signature(&config, "COMMITTER"));
     let mut parents: Vec<Oid> = Vec::new();
     // Include all commits from tree, to keep them reachable and fetchable.
@@ -919,63 +1978,184 @@ fn commit_status(out: &mut Output, repo: &Repository, m: &ArgMatches, do_status:
 
     if commit_all {
         internals.staged = try!(repo.treebuilder(Some(&tree)));
-        try!(internals.write(repo));
+        try!(internals.write(repo, verbosity));
+    } else if let Some(names) = commit_only {
+        for name in names {
+            match tree.get_name(name) {
+                Some(entry) => { try!(internals.staged.insert(name, entry.id(), entry.filemode())); }
+                None => {
+                    if try!(internals.staged.get(name)).is_some() {
+                        try!(internals.staged.remove(name));
+                    }
+                }
+            }
+        }
+        try!(internals.write(repo, verbosity));
+    }
+
+    if verbosity >= Verbosity::Verbose {
+        try!(writeln!(out, "Updated {}{} to {}", SERIES_PREFIX, series_name, new_commit_oid));
+    }
+    if verbosity > Verbosity::Quiet {
+        let (new_commit_short_id, new_commit_summary) = try!(commit_summarize_components(&repo, new_commit_oid, abbrev));
+        try!(writeln!(out, "[{} {}] {}", series_name, new_commit_short_id, new_commit_summary));
     }
 
-    let (new_commit_short_id, new_commit_summary) = try!(commit_summarize_components(&repo, new_commit_oid));
-    try!(writeln!(out, "[{} {}] {}", series_name, new_commit_short_id, new_commit_summary));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &std::fs::Metadata) -> bool {
+    true
+}
+
+// Find the hook name in core.hooksPath or the hooks directory of the repository, if it exists
+// and is executable.
+fn find_hook(repo: &Repository, config: &Config, name: &str) -> Result<Option<PathBuf>> {
+    let top = repo.workdir().unwrap_or(repo.path());
+    let dir = match try!(notfound_to_none(config.get_path("core.hooksPath"))) {
+        Some(dir) => top.join(dir),
+        None => repo.path().join("hooks"),
+    };
+    let path = dir.join(name);
+    match std::fs::metadata(&path) {
+        Ok(ref metadata) if metadata.is_file() && is_executable(metadata) => Ok(Some(path)),
+        _ => Ok(None),
+    }
+}
 
+// Run the hook name with args, if it exists, from the top of the working tree like git does; fail
+// if the hook fails.
+fn run_hook(repo: &Repository, config: &Config, name: &str, args: &[&OsStr]) -> Result<()> {
+    if let Some(path) = try!(find_hook(repo, config, name)) {
+        let top = repo.workdir().unwrap_or(repo.path());
+        let status = try!(Command::new(&path).args(args).current_dir(top).status());
+        if !status.success() {
+            return Err(format!("The {} hook exited with {}; not committing.\nUse \"git series commit --no-verify\" to skip the hooks.", name, status).into());
+        }
+    }
     Ok(())
 }
 
-fn cover(repo: &Repository, m: &ArgMatches) -> Result<()> {
+// Edit the blob entry name in the working version of the series, such as "cover", in an editor on
+// NAME_EDITMSG in the git directory, starting from its current content or else from template; with
+// --delete, remove it instead. what describes the entry in messages, and command is the subcommand
+// that edits it.
+fn edit_series_blob(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, name: &str, template: &str, what: &str, command: &str) -> Result<()> {
     let mut internals = try!(Internals::read(repo));
 
-    let (working_cover_id, working_cover_content) = match try!(internals.working.get("cover")) {
-        None => (zero_oid(), String::new()),
-        Some(entry) => (entry.id(), try!(std::str::from_utf8(try!(repo.find_blob(entry.id())).content())).to_string()),
+    let (working_id, working_blob) = match try!(internals.working.get(name)) {
+        None => (zero_oid(), None),
+        Some(entry) => (entry.id(), Some(try!(repo.find_blob(entry.id())))),
     };
 
     if m.is_present("delete") {
-        if working_cover_id.is_zero() {
-            return Err("No cover to delete".into());
+        if working_id.is_zero() {
+            return Err(format!("No {} to delete", what).into());
+        }
+        try!(internals.working.remove(name));
+        try!(internals.write(repo, verbosity));
+        if verbosity > Verbosity::Quiet {
+            println!("Deleted {}", what);
         }
-        try!(internals.working.remove("cover"));
-        try!(internals.write(repo));
-        println!("Deleted cover letter");
         return Ok(());
     }
 
-    let filename = repo.path().join("COVER_EDITMSG");
+    // The edited text must be UTF-8, so refuse before the user spends any effort editing.
+    if let Some(ref blob) = working_blob {
+        if let Err(e) = std::str::from_utf8(blob.content()) {
+            return Err(format!("Cannot edit the {}: it is not valid UTF-8: {}", what, e).into());
+        }
+    }
+
+    let filename = repo.path().join(format!("{}_EDITMSG", name.to_uppercase()));
     let mut file = try!(File::create(&filename));
-    if working_cover_content.is_empty() {
-        try!(write!(file, "{}", COVER_LETTER_COMMENT));
-    } else {
-        try!(write!(file, "{}", working_cover_content));
+    match working_blob {
+        Some(ref blob) if !blob.content().is_empty() => try!(file.write_all(blob.content())),
+        _ => try!(write!(file, "{}", template)),
     }
     drop(file);
     let config = try!(repo.config());
     try!(run_editor(&config, &filename));
-    let mut file = try!(File::open(&filename));
-    let mut msg = String::new();
-    try!(file.read_to_string(&mut msg));
+    let msg = try!(read_edited_file(&filename));
     let msg = try!(git2::message_prettify(msg, git2::DEFAULT_COMMENT_CHAR));
     if msg.is_empty() {
-        return Err("Empty cover letter; not changing.\n(To delete the cover letter, use \"git series cover -d\".)".into());
+        return Err(format!("Empty {}; not changing.\n(To delete the {}, use \"git series {} -d\".)", what, what, command).into());
     }
 
-    let new_cover_id = try!(repo.blob(msg.as_bytes()));
-    if new_cover_id == working_cover_id {
-        println!("Cover letter unchanged");
+    let new_id = try!(repo.blob(msg.as_bytes()));
+    if new_id == working_id {
+        if verbosity > Verbosity::Quiet {
+            let capitalized: String = what.chars().take(1).flat_map(|c| c.to_uppercase()).chain(what.chars().skip(1)).collect();
+            println!("{} unchanged", capitalized);
+        }
     } else {
-        try!(internals.working.insert("cover", new_cover_id, GIT_FILEMODE_BLOB as i32));
-        try!(internals.write(repo));
-        println!("Updated cover letter");
+        try!(internals.working.insert(name, new_id, GIT_FILEMODE_BLOB as i32));
+        try!(internals.write(repo, verbosity));
+        if verbosity > Verbosity::Quiet {
+            println!("Updated {}", what);
+        }
     }
 
     Ok(())
 }
 
+fn cover(repo: &Repository, m: &ArgMatches, verbosity: Verbosity) -> Result<()> {
+    edit_series_blob(repo, m, verbosity, "cover", COVER_LETTER_COMMENT, "cover letter", "cover")
+}
+
+fn describe(repo: &Repository, m: &ArgMatches, verbosity: Verbosity) -> Result<()> {
+    edit_series_blob(repo, m, verbosity, "description", DESCRIPTION_COMMENT, "description", "describe")
+}
+
+fn do_config(repo: &Repository, m: &ArgMatches, verbosity: Verbosity) -> Result<()> {
+    let key = m.value_of("key").unwrap();
+    let mut internals = try!(Internals::read(repo));
+    let mut series_config = try!(SeriesConfig::read(repo, &internals));
+
+    let value = m.value_of("value");
+    if value.is_none() && !m.is_present("unset") {
+        let values = series_config.get_all(key);
+        if values.is_empty() {
+            return Err(format!("Series config \"{}\" not set", key).into());
+        }
+        for value in values {
+            println!("{}", value);
+        }
+        return Ok(());
+    }
+
+    if !m.is_present("add") {
+        let len = series_config.entries.len();
+        series_config.entries.retain(|&(ref k, _)| k != key);
+        if m.is_present("unset") && series_config.entries.len() == len {
+            return Err(format!("Series config \"{}\" not set", key).into());
+        }
+    }
+    if let Some(value) = value {
+        if value.contains('\n') {
+            return Err("Series config values cannot contain newlines".into());
+        }
+        series_config.entries.push((key.to_string(), value.to_string()));
+    }
+
+    if series_config.entries.is_empty() {
+        if try!(internals.working.get("config")).is_some() {
+            try!(internals.working.remove("config"));
+        }
+    } else {
+        let config_id = try!(repo.blob(series_config.to_string().as_bytes()));
+        try!(internals.working.insert("config", config_id, GIT_FILEMODE_BLOB as i32));
+    }
+    internals.write(repo, verbosity)
+}
+
 fn cp_mv(repo: &Repository, m: &ArgMatches, mv: bool) -> Result<()> {
     let shead_target = if let Some(shead) = try!(notfound_to_none(repo.find_reference(SHEAD_REF))) {
         Some(try!(shead_series_name(&shead)))
@@ -1013,13 +2193,39 @@ fn date_822(t: git2::Time) -> String {
     datetime.to_rfc2822()
 }
 
+// Parse a date given on the command line: either "@" followed by seconds since the epoch, or an
+// RFC 2822 date.
+fn parse_date(date: &str) -> Result<git2::Time> {
+    if date.starts_with('@') {
+        let seconds = try!(date[1..].parse::<i64>().map_err(|e| format!("Invalid date \"{}\": {}", date, e)));
+        return Ok(git2::Time::new(seconds, 0));
+    }
+    let datetime = try!(chrono::DateTime::parse_from_rfc2822(date).map_err(|e| format!("Invalid date \"{}\": {}", date, e)));
+    let offset_minutes = datetime.offset().local_minus_utc().num_minutes();
+    Ok(git2::Time::new(datetime.timestamp(), offset_minutes as i32))
+}
+
+#[test]
+fn test_parse_date() {
+    let tests = vec![
+        ("@864000", "Sun, 11 Jan 1970 00:00:00 +0000"),
+        ("Tue, 15 Jul 2003 10:52:37 +0200", "Tue, 15 Jul 2003 10:52:37 +0200"),
+        ("Tue, 15 Jul 2003 10:52:37 -0430", "Tue, 15 Jul 2003 10:52:37 -0430"),
+    ];
+    for (date, result) in tests {
+        assert_eq!(date_822(parse_date(date).unwrap()), result);
+    }
+    assert!(parse_date("@yesterday").is_err());
+    assert!(parse_date("yesterday").is_err());
+}
+
 fn shortlog(commits: &mut [Commit]) -> String {
     let mut s = String::new();
-    let mut author_map = std::collections::HashMap::new();
+    let mut author_map = HashMap::new();
 
     for mut commit in commits {
-        let author = commit.author().name().unwrap().to_string();
-        author_map.entry(author).or_insert(Vec::new()).push(commit.summary().unwrap().to_string());
+        let author = String::from_utf8_lossy(commit.author().name_bytes()).into_owned();
+        author_map.entry(author).or_insert(Vec::new()).push(commit_summary(&mut commit));
     }
 
     let mut authors: Vec<_> = author_map.keys().collect();
@@ -1045,12 +2251,14 @@ fn ascii_isalnum(c: char) -> bool {
     (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9')
 }
 
-fn sanitize_summary(summary: &str) -> String {
+// Turn a commit summary into a filename stem of at most max_len bytes: keep letters and digits,
+// including non-ASCII ones, along with '_' and '.', and replace each run of anything else with '-'.
+fn sanitize_summary(summary: &str, max_len: usize) -> String {
     let mut s = String::with_capacity(summary.len());
     let mut prev_dot = false;
     let mut need_space = false;
     for c in summary.chars() {
-        if ascii_isalnum(c) || c == '_' || c == '.' {
+        if c.is_alphanumeric() || c == '_' || c == '.' {
             if need_space {
                 s.push('-');
                 need_space = false;
@@ -1065,7 +2273,11 @@ fn sanitize_summary(summary: &str) -> String {
         }
         prev_dot = c == '.';
     }
-    let end = s.trim_right_matches(|c| c == '.' || c == '-').len();
+    let mut end = std::cmp::min(s.len(), max_len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    let end = s[..end].trim_end_matches(|c| c == '.' || c == '-').len();
     s.truncate(end);
     s
 }
@@ -1082,19 +2294,133 @@ fn test_sanitize_summary() {
         ("...Test...case...", ".Test.case"),
         ("...Test...case.!!", ".Test.case"),
         (".!.Test.!.case.!.", ".-.Test.-.case"),
+        ("Fix caf\u{e9} cr\u{e8}me", "Fix-caf\u{e9}-cr\u{e8}me"),
+        ("\u{4fee}\u{590d} \u{5d29}\u{6e83}", "\u{4fee}\u{590d}-\u{5d29}\u{6e83}"),
+        ("\u{41f}\u{440}\u{438}\u{432}\u{435}\u{442} \u{2014} \u{43c}\u{438}\u{440}", "\u{41f}\u{440}\u{438}\u{432}\u{435}\u{442}-\u{43c}\u{438}\u{440}"),
     ];
     for (summary, sanitized) in tests {
-        assert_eq!(sanitize_summary(summary), sanitized.to_string());
+        assert_eq!(sanitize_summary(summary, 64), sanitized.to_string());
+    }
+    let tests = vec![
+        ("Test case", 9, "Test-case"),
+        ("Test case", 6, "Test-c"),
+        ("Test case", 5, "Test"),
+        ("Test.. case", 5, "Test"),
+        ("\u{4fee}\u{590d}", 5, "\u{4fee}"),
+        ("Test", 0, ""),
+    ];
+    for (summary, max_len, sanitized) in tests {
+        assert_eq!(sanitize_summary(summary, max_len), sanitized.to_string());
     }
 }
 
+// The name of the file for patch n, at most max_len bytes long.
+fn patch_filename(n: usize, subject: &str, max_len: usize) -> String {
+    let number = format!("{:04}-", n);
+    let suffix = ".patch";
+    let stem = sanitize_summary(subject, max_len.saturating_sub(number.len() + suffix.len()));
+    format!("{}{}{}", number, stem, suffix)
+}
+
+// Split a commit message into its subject and body. Like git, trim only ASCII whitespace from the
+// end of the subject, keeping other Unicode whitespace such as a non-breaking space.
 fn split_message(message: &str) -> (&str, &str) {
     let mut iter = message.splitn(2, '\n');
-    let subject = iter.next().unwrap().trim_right();
-    let body = iter.next().map(|s| s.trim_left()).unwrap_or("");
+    let subject = iter.next().unwrap().trim_end_matches(|c| c == ' ' || c == '\t' || c == '\r');
+    let body = iter.next().map(|s| s.trim_start()).unwrap_or("");
     (subject, body)
 }
 
+#[test]
+fn test_split_message() {
+    let tests = vec![
+        ("", "", ""),
+        ("Subject", "Subject", ""),
+        ("Subject\n\nBody\n", "Subject", "Body\n"),
+        ("Subject \t \r\n \n\tBody\n", "Subject", "Body\n"),
+        ("Subject\u{a0}\n\nBody\n", "Subject\u{a0}", "Body\n"),
+        ("Subject\u{a0} \t\n\nBody\n", "Subject\u{a0}", "Body\n"),
+    ];
+    for (message, subject, body) in tests {
+        assert_eq!(split_message(message), (subject, body));
+    }
+}
+
+fn is_trailer(line: &str) -> bool {
+    match line.find(": ") {
+        Some(pos) => pos > 0 && line[..pos].chars().all(|c| ascii_isalnum(c) || c == '-'),
+        None => false,
+    }
+}
+
+// Append the trailer line to msg, unless msg already ends with it. Separate it from the body with a
+// blank line unless msg already ends in a block of trailers.
+fn append_trailer(msg: &str, trailer: &str) -> String {
+    let msg = msg.trim_end();
+    let last_line = msg.lines().last().unwrap_or("");
+    if last_line == trailer {
+        format!("{}\n", msg)
+    } else if msg.is_empty() {
+        format!("{}\n", trailer)
+    } else if is_trailer(last_line) {
+        format!("{}\n{}\n", msg, trailer)
+    } else {
+        format!("{}\n\n{}\n", msg, trailer)
+    }
+}
+
+#[test]
+fn test_append_trailer() {
+    let signoff = "Signed-off-by: A U Thor <author@example.com>";
+    let tests = vec![
+        ("", "Signed-off-by: A U Thor <author@example.com>\n"),
+        ("Subject\n", "Subject\n\nSigned-off-by: A U Thor <author@example.com>\n"),
+        ("Subject\n\nFixes: 0123456789ab (\"Subject\")\n", "Subject\n\nFixes: 0123456789ab (\"Subject\")\nSigned-off-by: A U Thor <author@example.com>\n"),
+        ("Subject\n\nReviewed-by: Someone <s@example.com>\n", "Subject\n\nReviewed-by: Someone <s@example.com>\nSigned-off-by: A U Thor <author@example.com>\n"),
+        ("Subject\n\nSigned-off-by: A U Thor <author@example.com>\n\n", "Subject\n\nSigned-off-by: A U Thor <author@example.com>\n"),
+        ("Subject\n\nSome text\n", "Subject\n\nSome text\n\nSigned-off-by: A U Thor <author@example.com>\n"),
+    ];
+    for (msg, result) in tests {
+        assert_eq!(append_trailer(msg, signoff), result.to_string());
+    }
+}
+
+// The trailers at the end of the body of a commit message, such as "Cc: Name <email>", as key and
+// value. Only a last paragraph consisting entirely of trailers counts.
+fn message_trailers(body: &str) -> Vec<(&str, &str)> {
+    let body = body.trim_end();
+    let last_paragraph = match body.rfind("\n\n") {
+        Some(pos) => &body[pos + 2..],
+        None => body,
+    };
+    if !last_paragraph.lines().all(is_trailer) {
+        return Vec::new();
+    }
+    last_paragraph.lines().map(|line| {
+        let pos = line.find(": ").unwrap();
+        (&line[..pos], line[pos + 2..].trim())
+    }).collect()
+}
+
+// The email address within an address such as "Name <email>", for comparing addresses.
+fn mail_address(address: &str) -> String {
+    match (address.rfind('<'), address.rfind('>')) {
+        (Some(l), Some(r)) if l < r => address[l + 1..r].to_lowercase(),
+        _ => address.trim().to_lowercase(),
+    }
+}
+
+#[test]
+fn test_message_trailers() {
+    assert!(message_trailers("").is_empty());
+    assert!(message_trailers("Some text\n").is_empty());
+    assert!(message_trailers("Some text\n\nCc: A <a@example.com>\nnot a trailer\n").is_empty());
+    assert_eq!(message_trailers("Some text\n\nCc: A <a@example.com>\nSigned-off-by: B <b@example.com>\n"),
+               vec![("Cc", "A <a@example.com>"), ("Signed-off-by", "B <b@example.com>")]);
+    assert_eq!(mail_address("A U Thor <Author@Example.com>"), "author@example.com");
+    assert_eq!(mail_address(" author@example.com "), "author@example.com");
+}
+
 struct DiffColors {
     commit: Style,
     meta: Style,
@@ -1139,12 +2465,143 @@ impl DiffColors {
     }
 }
 
+// Diff two trees, honoring the diff.algorithm and diff.renames settings from the git config.
+fn diff_trees<'repo>(repo: &'repo Repository, old_tree: Option<&Tree>, new_tree: Option<&Tree>) -> Result<Diff<'repo>> {
+    diff_trees_opts(repo, old_tree, new_tree, DiffOptions::new())
+}
+
+// Like diff_trees, but for a patch that git apply or git am should apply: include binary changes as
+// "GIT binary patch" data rather than "Binary files differ", which also makes libgit2 write full
+// object ids on the index line as git apply requires for binary patches.
+fn diff_trees_for_apply<'repo>(repo: &'repo Repository, old_tree: Option<&Tree>, new_tree: Option<&Tree>) -> Result<Diff<'repo>> {
+    let mut opts = DiffOptions::new();
+    opts.show_binary(true);
+    diff_trees_opts(repo, old_tree, new_tree, opts)
+}
+
+fn diff_trees_opts<'repo>(repo: &'repo Repository, old_tree: Option<&Tree>, new_tree: Option<&Tree>, mut opts: DiffOptions) -> Result<Diff<'repo>> {
+    let config = try!(repo.config());
+    match try!(notfound_to_none(config.get_string("diff.algorithm"))).as_ref().map(|a| a.as_str()) {
+        None | Some("myers") | Some("default") => {}
+        Some("minimal") => { opts.minimal(true); }
+        // libgit2 doesn't implement histogram; patience is the closest algorithm it has.
+        Some("patience") | Some("histogram") => { opts.patience(true); }
+        Some(a) => { return Err(format!("Unknown diff.algorithm \"{}\"", a).into()); }
+    }
+    let mut diff = try!(repo.diff_tree_to_tree(old_tree, new_tree, Some(&mut opts)));
+
+    if let Some(renames) = try!(notfound_to_none(config.get_string("diff.renames"))) {
+        let mut find_opts = DiffFindOptions::new();
+        match renames.to_lowercase().as_str() {
+            "copies" | "copy" => { find_opts.renames(true).copies(true); }
+            v => {
+                if !try!(Config::parse_bool(v).map_err(|e| format!("Error parsing diff.renames: {}", e))) {
+                    return Ok(diff);
+                }
+                find_opts.renames(true);
+            }
+        }
+        try!(diff.find_similar(Some(&mut find_opts)));
+    }
+    Ok(diff)
+}
+
 fn diffstat(diff: &Diff) -> Result<String> {
     let stats = try!(diff.stats());
     let stats_buf = try!(stats.to_buf(git2::DIFF_STATS_FULL|git2::DIFF_STATS_INCLUDE_SUMMARY, 72));
     Ok(stats_buf.as_str().unwrap().to_string())
 }
 
+// Check the lines a patch adds for whitespace errors: trailing whitespace, and a space before a tab
+// in the indentation. Return a description of each error, and the patch with the errors fixed. The
+// fixed patch omits the index line of each file it changes, since the blob ids on that line no
+// longer match the result of applying it.
+fn check_whitespace(patch: &str) -> (Vec<String>, String) {
+    // Remove the index line at the given range of fixed, if the file had any fixes.
+    fn drop_index_line(fixed: &mut String, index_line: Option<(usize, usize)>, file_fixed: bool) {
+        if let (true, Some((start, end))) = (file_fixed, index_line) {
+            fixed.drain(start..end);
+        }
+    }
+    let mut errors = Vec::new();
+    let mut fixed = String::new();
+    let mut path = "";
+    let mut in_hunk = false;
+    let mut line_number = 0;
+    let mut index_line = None;
+    let mut file_fixed = false;
+    for line in patch.lines() {
+        if line.starts_with("diff --git ") {
+            drop_index_line(&mut fixed, index_line.take(), file_fixed);
+            file_fixed = false;
+            in_hunk = false;
+        } else if !in_hunk && line.starts_with("index ") {
+            let start = fixed.len();
+            writeln!(fixed, "{}", line).unwrap();
+            index_line = Some((start, fixed.len()));
+            continue;
+        } else if !in_hunk && line.starts_with("+++ ") {
+            path = if line[4..].starts_with("b/") { &line[6..] } else { &line[4..] };
+        } else if line.starts_with("@@ ") {
+            in_hunk = true;
+            line_number = line.split(' ').nth(2)
+                .and_then(|range| range.trim_start_matches('+').split(',').next())
+                .and_then(|start| start.parse::<usize>().ok())
+                .unwrap_or(0);
+            writeln!(fixed, "{}", line).unwrap();
+            continue;
+        } else if in_hunk && line.starts_with('+') {
+            let content = &line[1..];
+            let indent_len = content.len() - content.trim_start().len();
+            let indent = &content[..indent_len];
+            if content.trim_end().len() < content.len() {
+                errors.push(format!("{}:{}: trailing whitespace", path, line_number));
+            }
+            let mut fixed_content = content.to_string();
+            if indent.contains(" \t") {
+                errors.push(format!("{}:{}: space before tab in indent", path, line_number));
+                let last_tab = indent.rfind('\t').unwrap();
+                let new_indent: String = indent[..last_tab].chars().filter(|&c| c != ' ').collect();
+                fixed_content = format!("{}{}", new_indent, &content[last_tab..]);
+            }
+            let fixed_content = fixed_content.trim_end();
+            if fixed_content != content {
+                file_fixed = true;
+            }
+            writeln!(fixed, "+{}", fixed_content).unwrap();
+            line_number += 1;
+            continue;
+        } else if in_hunk && line.starts_with(' ') {
+            line_number += 1;
+        }
+        writeln!(fixed, "{}", line).unwrap();
+    }
+    drop_index_line(&mut fixed, index_line, file_fixed);
+    (errors, fixed)
+}
+
+#[test]
+fn test_check_whitespace() {
+    let patch = "diff --git a/f b/f\nindex 1234567..89abcde 100644\n--- a/f\n+++ b/f\n@@ -1,2 +1,4 @@\n context\n-old\n+new \n+ \tindented\n+++ fine\n";
+    let (errors, fixed) = check_whitespace(patch);
+    assert_eq!(errors, vec!["f:2: trailing whitespace", "f:3: space before tab in indent"]);
+    assert_eq!(fixed, "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1,2 +1,4 @@\n context\n-old\n+new\n+\tindented\n+++ fine\n");
+    let patch = "diff --git a/g b/g\nindex 1234567..89abcde 100644\n--- a/g\n+++ b/g\n@@ -3 +3 @@\n-x\n+y\n";
+    let (errors, fixed) = check_whitespace(patch);
+    assert!(errors.is_empty());
+    assert_eq!(fixed, patch);
+    let (errors, fixed) = check_whitespace("diff --git a/h b/h\n--- a/h\n+++ b/h\n@@ -0,0 +1 @@\n+ \t\n");
+    assert_eq!(errors, vec!["h:1: trailing whitespace", "h:1: space before tab in indent"]);
+    assert_eq!(fixed, "diff --git a/h b/h\n--- a/h\n+++ b/h\n@@ -0,0 +1 @@\n+\n");
+    let patch = "diff --git a/f b/f\nindex 1234567..89abcde 100644\n--- a/f\n+++ b/f\n@@ -1 +1 @@\n+x \ndiff --git a/g b/g\nindex 7654321..edcba98 100644\n--- a/g\n+++ b/g\n@@ -1 +1 @@\n+y\n";
+    let (_, fixed) = check_whitespace(patch);
+    assert_eq!(fixed, "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1 +1 @@\n+x\ndiff --git a/g b/g\nindex 7654321..edcba98 100644\n--- a/g\n+++ b/g\n@@ -1 +1 @@\n+y\n");
+}
+
+// Write diff as a patch. Other than simplified output, this writes libgit2's lines unchanged apart
+// from colors, so the patch applies like git's own: file headers ('F') carry any mode, new/deleted
+// file, and index lines; binary data ('B') passes through whole; and only context, added and removed
+// lines (' ', '+', '-') need their origin character prepended.
 fn write_diff<W: IoWrite>(f: &mut W, colors: &DiffColors, diff: &Diff, simplify: bool) -> Result<usize> {
     let mut err = Ok(());
     let mut lines = 0;
@@ -1217,44 +2674,310 @@ fn write_diff<W: IoWrite>(f: &mut W, colors: &DiffColors, diff: &Diff, simplify:
     Ok(lines)
 }
 
-fn get_commits(repo: &Repository, base: Oid, series: Oid) -> Result<Vec<Commit>> {
+// The commits of a patch series from base to series, oldest first. Unless allow_merges, fail on a
+// merge commit.
+fn series_commits(repo: &Repository, series: Oid, base: Oid, allow_merges: bool, abbrev: usize) -> Result<Vec<Commit>> {
+    walk_commits(repo, series, Some(base), allow_merges, abbrev)
+}
+
+// The commits reachable from series and not from base, oldest first; without a base, all the
+// commits back to the root. Unless allow_merges, fail on a merge commit.
+fn walk_commits(repo: &Repository, series: Oid, base: Option<Oid>, allow_merges: bool, abbrev: usize) -> Result<Vec<Commit>> {
     let mut revwalk = try!(repo.revwalk());
     revwalk.set_sorting(git2::SORT_TOPOLOGICAL|git2::SORT_REVERSE);
     try!(revwalk.push(series));
-    try!(revwalk.hide(base));
+    if let Some(base) = base {
+        try!(revwalk.hide(base));
+    }
     revwalk.map(|c| {
         let id = try!(c);
-        let commit = try!(repo.find_commit(id));
+        let mut commit = try!(repo.find_commit(id));
+        if !allow_merges && commit.parent_ids().count() > 1 {
+            return Err(format!("Error: patch series contains merge commit:\n{}", try!(commit_obj_summarize(&mut commit, abbrev))).into());
+        }
         Ok(commit)
     }).collect()
 }
 
-fn write_commit_range_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &DiffColors, (base1, series1): (Oid, Oid), (base2, series2): (Oid, Oid)) -> Result<()> {
-    let mut commits1 = try!(get_commits(repo, base1, series1));
-    let mut commits2 = try!(get_commits(repo, base2, series2));
-    for commit in commits1.iter().chain(commits2.iter()) {
-        if commit.parent_ids().count() > 1 {
-            try!(writeln!(out, "(Diffs of series with merge commits ({}) not yet supported)", commit.id()));
-            return Ok(());
-        }
+// Whether base is an ancestor of (or the same commit as) series, so that base..series makes sense
+// as a patch series.
+fn is_ancestor(repo: &Repository, base: Oid, series: Oid) -> Result<bool> {
+    Ok(base == series || try!(repo.graph_descendant_of(series, base)))
+}
+
+// The patches of a patch series, for commands that need at least one patch to operate on. Fail
+// consistently if the series has no patches or if base is not an ancestor of the series.
+fn series_patches(repo: &Repository, series: Oid, base: Oid, allow_merges: bool, abbrev: usize) -> Result<Vec<Commit>> {
+    if series == base {
+        return Err("No patches: series tip equals base; add commits or set a different base with \"git series base\"".into());
     }
-    let ncommon = commits1.iter().zip(commits2.iter()).take_while(|&(ref c1, ref c2)| c1.id() == c2.id()).count();
-    drop(commits1.drain(..ncommon));
-    drop(commits2.drain(..ncommon));
-    let ncommits1 = commits1.len();
-    let ncommits2 = commits2.len();
-    let n = ncommits1 + ncommits2;
-    if n == 0 {
-        return Ok(());
+    if !try!(is_ancestor(repo, base, series)) {
+        let (base_short, base_summary) = try!(commit_summarize_components(repo, base, abbrev));
+        return Err(format!("Base {} ({}) is not an ancestor of the series.\nUse \"git series base\" to set a new base, or \"git series rebase\" to rebase the series onto it.", base_short, base_summary).into());
     }
-    let commit_text = &|commit: &Commit| {
-        let parent = try!(commit.parent(0));
-        let author = commit.author();
-        let diff = try!(repo.diff_tree_to_tree(Some(&parent.tree().unwrap()), Some(&commit.tree().unwrap()), None));
-        let mut v = Vec::new();
-        try!(v.write_all(b"From: "));
-        try!(v.write_all(author.name_bytes()));
-        try!(v.write_all(b" <"));
+    series_commits(repo, series, base, allow_merges, abbrev)
+}
+
+// SHA-1 of data, for patch-ids; libgit2 has no API to hash data that isn't an object.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    let bits = (data.len() as u64).wrapping_mul(8);
+    for shift in (0..8).rev() {
+        msg.push((bits >> (shift * 8)) as u8);
+    }
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (w, word) in w.iter_mut().zip(chunk.chunks(4)) {
+            *w = (word[0] as u32) << 24 | (word[1] as u32) << 16 | (word[2] as u32) << 8 | word[3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &w) in w.iter().enumerate() {
+            let (f, k) = if i < 20 {
+                ((b & c) | (!b & d), 0x5a827999)
+            } else if i < 40 {
+                (b ^ c ^ d, 0x6ed9eba1)
+            } else if i < 60 {
+                ((b & c) | (b & d) | (c & d), 0x8f1bbcdc)
+            } else {
+                (b ^ c ^ d, 0xca62c1d6)
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+    let mut hash = [0u8; 20];
+    for (bytes, v) in hash.chunks_mut(4).zip(h.iter()) {
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = (v >> (24 - 8 * i)) as u8;
+        }
+    }
+    hash
+}
+
+#[test]
+fn test_sha1() {
+    let hex = |data: &[u8]| sha1(data).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    assert_eq!(hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hex(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+}
+
+// Parse the line counts from a hunk header "@@ -a,b +c,d @@", where a missing count means 1.
+fn hunk_header_counts(line: &[u8]) -> (i64, i64) {
+    let line = String::from_utf8_lossy(line);
+    let count = |range: Option<&str>| range.and_then(|r| r.find(',').map(|i| &r[i + 1..])).map_or(1, |n| n.parse().unwrap_or(0));
+    let mut ranges = line["@@ ".len()..].split(' ');
+    let before = count(ranges.next());
+    (before, count(ranges.next()))
+}
+
+// Compute the stable patch-id of a patch, as "git patch-id --stable" does: the sum of the SHA-1
+// hashes of the diff of each file, ignoring whitespace and line numbers. A binary diff counts as
+// the object ids on its index line.
+fn patch_id_of(patch: &[u8]) -> String {
+    fn flush(result: &mut [u8; 20], file: &mut Vec<u8>) {
+        let mut carry = 0u16;
+        for (r, h) in result.iter_mut().zip(sha1(file).iter()) {
+            carry += *r as u16 + *h as u16;
+            *r = carry as u8;
+            carry >>= 8;
+        }
+        file.clear();
+    }
+    let mut result = [0u8; 20];
+    let mut file = Vec::new();
+    let mut hashed_any = false;
+    let mut binary = false;
+    let (mut before, mut after) = (-1, -1);
+    let (mut pre_id, mut post_id): (&[u8], &[u8]) = (b"", b"");
+    for line in patch.split(|&b| b == b'\n') {
+        if !hashed_any && !line.starts_with(b"diff ") {
+            continue;
+        }
+        // Parsing the header of a file.
+        if before == -1 {
+            if line.starts_with(b"GIT binary patch") || line.starts_with(b"Binary files") {
+                binary = true;
+                before = 0;
+                file.extend_from_slice(pre_id);
+                file.extend_from_slice(post_id);
+                flush(&mut result, &mut file);
+                continue;
+            } else if line.starts_with(b"index ") {
+                let ids = &line["index ".len()..];
+                if let Some(dots) = ids.windows(2).position(|w| w == b"..") {
+                    let end = ids[dots..].iter().position(|&b| b == b' ').map_or(ids.len(), |n| dots + n);
+                    pre_id = &ids[..dots];
+                    post_id = &ids[dots + 2..end];
+                }
+                continue;
+            } else if line.starts_with(b"--- ") {
+                before = 1;
+                after = 1;
+            } else if line.first().map(|b| b.is_ascii_alphabetic()) != Some(true) {
+                break;
+            }
+        }
+        if binary {
+            if line.starts_with(b"diff ") {
+                binary = false;
+                before = -1;
+            }
+            continue;
+        }
+        // Like git, ignore "\ No newline at end of file".
+        if line.starts_with(b"\\") {
+            continue;
+        }
+        // Between hunks: either another hunk, or the header of another file.
+        if before == 0 && after == 0 {
+            if line.starts_with(b"@@ -") {
+                let (b, a) = hunk_header_counts(line);
+                before = b;
+                after = a;
+                continue;
+            }
+            if !line.starts_with(b"diff ") {
+                break;
+            }
+            flush(&mut result, &mut file);
+            before = -1;
+            after = -1;
+        }
+        match line.first() {
+            Some(&b'-') => { before -= 1; }
+            Some(&b'+') => { after -= 1; }
+            Some(&b' ') => { before -= 1; after -= 1; }
+            _ => {}
+        }
+        let len = file.len();
+        file.extend(line.iter().filter(|b| !b" \t\r\x0b\x0c".contains(b)));
+        hashed_any |= file.len() > len;
+    }
+    flush(&mut result, &mut file);
+    result.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_patch_id_of() {
+    // The ids from "git patch-id --stable".
+    let patch = concat!(
+        "diff --git a/a b/a\n",
+        "index 4cb29ea..d0c4180 100644\n",
+        "--- a/a\n",
+        "+++ b/a\n",
+        "@@ -1,3 +1,4 @@\n",
+        " one\n",
+        "-two\n",
+        "+  two changed\t\n",
+        " three\n",
+        "+four\n",
+        "diff --git a/b b/b\n",
+        "new file mode 100644\n",
+        "index 0000000..3e75765\n",
+        "--- /dev/null\n",
+        "+++ b/b\n",
+        "@@ -0,0 +1 @@\n",
+        "+new\n",
+        "diff --git a/nonl b/nonl\n",
+        "index c1b0730..975fbec 100644\n",
+        "--- a/nonl\n",
+        "+++ b/nonl\n",
+        "@@ -1 +1 @@\n",
+        "-x\n",
+        "\\ No newline at end of file\n",
+        "+y\n",
+    );
+    assert_eq!(patch_id_of(patch.as_bytes()), "764fd649e407c4b366269727f367852808e1163f");
+    let patch = concat!(
+        "diff --git a/a b/a\n",
+        "old mode 100644\n",
+        "new mode 100755\n",
+        "diff --git a/b b/b\n",
+        "deleted file mode 100644\n",
+        "index 3e75765..0000000\n",
+        "--- a/b\n",
+        "+++ /dev/null\n",
+        "@@ -1 +0,0 @@\n",
+        "-new\n",
+        "diff --git a/bin b/bin\n",
+        "new file mode 100644\n",
+        "index 0000000..88768ef\n",
+        "Binary files /dev/null and b/bin differ\n",
+    );
+    assert_eq!(patch_id_of(patch.as_bytes()), "6716d2e5834453eb9ae661eff72aa140b77e11c0");
+}
+
+// Compute the stable patch-id of a commit, the hash of its diff against its first parent, or
+// against the empty tree for a root commit.
+fn patch_id(repo: &Repository, commit: &Commit) -> Result<String> {
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(try!(parent.tree())),
+        None => None,
+    };
+    let diff = try!(diff_trees(repo, parent_tree.as_ref(), Some(&try!(commit.tree()))));
+    let mut patch = Vec::new();
+    try!(write_diff(&mut patch, &DiffColors::plain(), &diff, false));
+    Ok(patch_id_of(&patch))
+}
+
+fn patch_ids(out: &mut Output, repo: &Repository, m: &ArgMatches, abbrev: usize) -> Result<()> {
+    let shead_commit = try!(peel_to_commit(try!(try!(repo.find_reference(SHEAD_REF)).resolve())));
+    let stree = try!(shead_commit.tree());
+    let series = try!(stree.get_name("series").ok_or("Internal error: series did not contain \"series\""));
+    let base = try!(stree.get_name("base").ok_or("Cannot compute patch-ids; no base set.\nUse \"git series base\" to set base."));
+    let base_id = try!(resolve_base(repo, &stree, base.id(), series.id()));
+    let (base_id, series_id) = match m.value_of("rev") {
+        Some(rev) => try!(previous_version(repo, rev, base_id)),
+        None => (base_id, series.id()),
+    };
+    for commit in try!(series_patches(repo, series_id, base_id, false, abbrev)) {
+        try!(writeln!(out, "{} {}", try!(patch_id(repo, &commit)), commit.id()));
+    }
+    Ok(())
+}
+
+fn write_commit_range_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &DiffColors, (base1, series1): (Oid, Oid), (base2, series2): (Oid, Oid), abbrev: usize) -> Result<()> {
+    let mut commits1 = try!(series_commits(repo, series1, base1, true, abbrev));
+    let mut commits2 = try!(series_commits(repo, series2, base2, true, abbrev));
+    for commit in commits1.iter().chain(commits2.iter()) {
+        if commit.parent_ids().count() > 1 {
+            try!(writeln!(out, "(Diffs of series with merge commits ({}) not yet supported)", commit.id()));
+            return Ok(());
+        }
+    }
+    let ncommon = commits1.iter().zip(commits2.iter()).take_while(|&(ref c1, ref c2)| c1.id() == c2.id()).count();
+    drop(commits1.drain(..ncommon));
+    drop(commits2.drain(..ncommon));
+    let ncommits1 = commits1.len();
+    let ncommits2 = commits2.len();
+    let n = ncommits1 + ncommits2;
+    if n == 0 {
+        return Ok(());
+    }
+    let commit_text = &|commit: &Commit| {
+        let parent = try!(commit.parent(0));
+        let author = commit.author();
+        let diff = try!(diff_trees(repo, Some(&try!(parent.tree())), Some(&try!(commit.tree()))));
+        let mut v = Vec::new();
+        try!(v.write_all(b"From: "));
+        try!(v.write_all(author.name_bytes()));
+        try!(v.write_all(b" <"));
         try!(v.write_all(author.email_bytes()));
         try!(v.write_all(b">\n\n"));
         try!(v.write_all(commit.message_bytes()));
@@ -1339,8 +3062,8 @@ fn write_commit_range_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &
 
     let offset = ncommon + 1;
     let nwidth = max(ncommits1 + offset, ncommits2 + offset).to_string().len();
-    let commits1_summaries: Vec<_> = try!(commits1.iter_mut().map(commit_obj_summarize_components).collect());
-    let commits2_summaries: Vec<_> = try!(commits2.iter_mut().map(commit_obj_summarize_components).collect());
+    let commits1_summaries: Vec<_> = try!(commits1.iter_mut().map(|c| commit_obj_summarize_components(c, abbrev)).collect());
+    let commits2_summaries: Vec<_> = try!(commits2.iter_mut().map(|c| commit_obj_summarize_components(c, abbrev)).collect());
     let idwidth = commits1_summaries.iter().chain(commits2_summaries.iter()).map(|&(ref short_id, _)| short_id.len()).max().unwrap();
     for commit_pair in commit_pairs {
         match commit_pair {
@@ -1395,8 +3118,8 @@ fn write_commit_range_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &
     Ok(())
 }
 
-fn write_series_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &DiffColors, tree1: Option<&Tree>, tree2: Option<&Tree>) -> Result<()> {
-    let diff = try!(repo.diff_tree_to_tree(tree1, tree2, None));
+fn write_series_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &DiffColors, tree1: Option<&Tree>, tree2: Option<&Tree>, abbrev: usize) -> Result<()> {
+    let diff = try!(diff_trees(repo, tree1, tree2));
     try!(write_diff(out, colors, &diff, false));
 
     let base1 = tree1.and_then(|t| t.get_name("base"));
@@ -1405,7 +3128,7 @@ fn write_series_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &DiffCo
     let series2 = tree2.and_then(|t| t.get_name("series"));
 
     if let (Some(base1), Some(series1), Some(base2), Some(series2)) = (base1, series1, base2, series2) {
-        try!(write_commit_range_diff(out, repo, colors, (base1.id(), series1.id()), (base2.id(), series2.id())));
+        try!(write_commit_range_diff(out, repo, colors, (base1.id(), series1.id()), (base2.id(), series2.id()), abbrev));
     } else {
         try!(writeln!(out, "Can't diff series: both versions must have base and series to diff"));
     }
@@ -1413,8 +3136,80 @@ fn write_series_diff<W: IoWrite>(out: &mut W, repo: &Repository, colors: &DiffCo
     Ok(())
 }
 
-fn mail_signature() -> String {
-    format!("-- \ngit-series {}", crate_version!())
+// If the cover letter differs between two versions of a series, write the changes to it as a
+// plain text diff, without the headers of a patch, for reviewing the prose.
+fn write_cover_diff<W: IoWrite>(out: &mut W, repo: &Repository, tree1: Option<&Tree>, tree2: Option<&Tree>) -> Result<()> {
+    let cover1 = tree1.and_then(|t| t.get_name("cover")).map(|e| e.id());
+    let cover2 = tree2.and_then(|t| t.get_name("cover")).map(|e| e.id());
+    if cover1 == cover2 {
+        return Ok(());
+    }
+    try!(writeln!(out, "Changes to the cover letter:\n"));
+    try!(write_blob_diff(out, repo, "cover", cover1, cover2));
+    try!(writeln!(out, ""));
+    Ok(())
+}
+
+// Write the changes between two versions of a blob entry of a series, such as the cover letter,
+// as a plain text diff without the headers of a patch.
+fn write_blob_diff<W: IoWrite>(out: &mut W, repo: &Repository, name: &str, blob1: Option<Oid>, blob2: Option<Oid>) -> Result<()> {
+    let mut trees = Vec::new();
+    for blob in [blob1, blob2].iter() {
+        let mut tb = try!(repo.treebuilder(None));
+        if let Some(id) = *blob {
+            try!(tb.insert(name, id, GIT_FILEMODE_BLOB as i32));
+        }
+        trees.push(try!(repo.find_tree(try!(tb.write()))));
+    }
+    let diff = try!(diff_trees(repo, Some(&trees[0]), Some(&trees[1])));
+    try!(write_diff(out, &DiffColors::plain(), &diff, true));
+    Ok(())
+}
+
+// Get the signature block to append to mails, honoring format.signatureFile and format.signature
+// like git format-patch. Returns None if the signature is disabled or configured as empty.
+fn mail_signature(config: &Config, m: &ArgMatches) -> Result<Option<String>> {
+    if m.is_present("no-signature") {
+        return Ok(None);
+    }
+    let signature = if let Some(signature) = m.value_of("signature") {
+        signature.to_string()
+    } else if let Some(path) = try!(notfound_to_none(config.get_path("format.signatureFile"))) {
+        let mut signature = String::new();
+        try!(try!(File::open(&path).map_err(|e| format!("Could not read format.signatureFile {}: {}", path.display(), e))).read_to_string(&mut signature));
+        signature
+    } else if let Some(signature) = try!(notfound_to_none(config.get_string("format.signature"))) {
+        signature
+    } else {
+        format!("git-series {}", crate_version!())
+    };
+    let signature = signature.trim_end_matches('\n');
+    if signature.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(format!("-- \n{}", signature)))
+    }
+}
+
+// Message-Ids take the form <{kind.}{object id}.{seconds}.{nonce}.git-series.{email}>, where the
+// object id identifies the commit or series version the mail describes, seconds and email come
+// from the sender's signature, and the nonce differs on every invocation so that formatting the
+// same series twice within a second still produces distinct ids. All the mails from one
+// invocation share the same suffix. With a fixed date, the seconds come from that date and the
+// nonce from the ids of the series and its commits, so formatting the same series again produces
+// the same ids.
+fn message_id_suffix(sender: &git2::Signature, date: Option<&git2::Time>, ids: &[Oid]) -> String {
+    let (seconds, nonce) = match date {
+        Some(date) => {
+            let nonce = ids.iter().flat_map(|id| id.as_bytes().iter()).fold(0u64, |n, &b| n.rotate_left(5) ^ b as u64);
+            (date.seconds(), format!("{:x}", nonce))
+        }
+        None => {
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or(std::time::Duration::from_secs(0));
+            (sender.when().seconds(), format!("{:x}{:x}", now.subsec_nanos(), std::process::id()))
+        }
+    };
+    format!("{}.{}.git-series.{}", seconds, nonce, String::from_utf8_lossy(sender.email_bytes()))
 }
 
 fn ensure_space(s: &str) -> &'static str {
@@ -1425,6 +3220,195 @@ fn ensure_space(s: &str) -> &'static str {
     }
 }
 
+fn is_ascii(bytes: &[u8]) -> bool {
+    bytes.iter().all(|&b| b < 0x80)
+}
+
+// Encode a mail header value containing non-ASCII characters as RFC 2047 "Q" encoded-words, each
+// at most 75 characters long.
+fn mail_encode_header(value: &str) -> String {
+    if is_ascii(value.as_bytes()) {
+        return value.to_string();
+    }
+    const PREFIX: &'static str = "=?UTF-8?q?";
+    const SUFFIX: &'static str = "?=";
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let mut encoded = String::new();
+        if c == ' ' {
+            encoded.push('_');
+        } else if c > ' ' && c < '\x7f' && c != '=' && c != '?' && c != '_' {
+            encoded.push(c);
+        } else {
+            for b in c.to_string().bytes() {
+                write!(encoded, "={:02X}", b).unwrap();
+            }
+        }
+        if !word.is_empty() && PREFIX.len() + word.len() + encoded.len() + SUFFIX.len() > 75 {
+            words.push(word);
+            word = String::new();
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+    let words: Vec<String> = words.iter().map(|word| format!("{}{}{}", PREFIX, word, SUFFIX)).collect();
+    words.join(" ")
+}
+
+// Fold a mail header at spaces so that each line fits in 78 columns where possible, as RFC 5322
+// recommends.
+fn fold_header(name: &str, value: &str) -> String {
+    let mut header = format!("{}:", name);
+    let mut line_len = header.len();
+    let mut line_empty = true;
+    for word in value.split(' ') {
+        if !line_empty && line_len + 1 + word.len() > 78 {
+            header.push('\n');
+            line_len = 0;
+        }
+        header.push(' ');
+        header.push_str(word);
+        line_len += 1 + word.len();
+        line_empty = false;
+    }
+    header
+}
+
+// Encode the name in a "Name <email>" identity for a mail header.
+fn mail_encode_ident(ident: &str) -> String {
+    match ident.rfind('<') {
+        Some(pos) => format!("{} {}", mail_encode_header(ident[..pos].trim()), &ident[pos..]),
+        None => mail_encode_header(ident),
+    }
+}
+
+#[test]
+fn test_fold_header() {
+    assert_eq!(fold_header("Subject", "[PATCH] Fix a bug"), "Subject: [PATCH] Fix a bug");
+    let subject = "[PATCH 0/3] A cover letter subject long enough that it cannot fit on a single line of a mail header";
+    let folded = fold_header("Subject", subject);
+    assert_eq!(folded, "Subject: [PATCH 0/3] A cover letter subject long enough that it cannot fit on\n a single line of a mail header");
+    assert_eq!(folded.replace("\n ", " "), format!("Subject: {}", subject));
+    let word = std::iter::repeat("x").take(80).collect::<String>();
+    assert_eq!(fold_header("References", &format!("{} <a@b>", word)), format!("References: {}\n <a@b>", word));
+}
+
+#[test]
+fn test_mail_encode_header() {
+    assert_eq!(mail_encode_header("[PATCH] Fix a bug"), "[PATCH] Fix a bug");
+    assert_eq!(mail_encode_header("[PATCH] Fix caf\u{e9}=?"), "=?UTF-8?q?[PATCH]_Fix_caf=C3=A9=3D=3F?=");
+    let long = mail_encode_header(&std::iter::repeat("\u{e9}").take(30).collect::<String>());
+    for line in long.split(' ') {
+        assert!(line.len() <= 75);
+        assert!(line.starts_with("=?UTF-8?q?") && line.ends_with("?="));
+    }
+    assert_eq!(long.replace("?= =?UTF-8?q?", ""), format!("=?UTF-8?q?{}?=", std::iter::repeat("=C3=A9").take(30).collect::<String>()));
+    assert_eq!(mail_encode_ident("A U Thor <author@example.com>"), "A U Thor <author@example.com>");
+    assert_eq!(mail_encode_ident("J\u{f6}rg <jorg@example.com>"), "=?UTF-8?q?J=C3=B6rg?= <jorg@example.com>");
+}
+
+// Encode a mail body as quoted-printable (RFC 2045), keeping its line breaks and using soft line
+// breaks to keep each line within 76 characters.
+fn quoted_printable(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (i, line) in body.split(|&b| b == b'\n').enumerate() {
+        if i > 0 {
+            out.push(b'\n');
+        }
+        let mut width = 0;
+        for (j, &b) in line.iter().enumerate() {
+            let last = j + 1 == line.len();
+            // Spaces and tabs at the end of a line would get lost in transit, so encode them.
+            let literal = (b >= 33 && b <= 126 && b != b'=') || ((b == b' ' || b == b'\t') && !last);
+            let encoded = if literal { vec![b] } else { format!("={:02X}", b).into_bytes() };
+            // Leave room for the "=" of a soft line break, unless this character ends the line.
+            if width + encoded.len() > if last { 76 } else { 75 } {
+                out.extend_from_slice(b"=\n");
+                width = 0;
+            }
+            width += encoded.len();
+            out.extend_from_slice(&encoded);
+        }
+    }
+    out
+}
+
+// Encode a mail body as base64 (RFC 2045), in lines of 76 characters.
+fn base64(body: &[u8]) -> Vec<u8> {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::new();
+    for (i, chunk) in body.chunks(3).enumerate() {
+        if i > 0 && i % 19 == 0 {
+            out.push(b'\n');
+        }
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (j, &b)| bits | (b as u32) << (16 - 8 * j));
+        for k in 0..4 {
+            out.push(if k <= chunk.len() { ALPHABET[(bits >> (18 - 6 * k)) as usize & 63] } else { b'=' });
+        }
+    }
+    if !body.is_empty() {
+        out.push(b'\n');
+    }
+    out
+}
+
+#[test]
+fn test_quoted_printable() {
+    assert_eq!(quoted_printable(b"plain text\n"), b"plain text\n".to_vec());
+    assert_eq!(quoted_printable("caf\u{e9} = x \n\tend\t\n".as_bytes()), b"caf=C3=A9 =3D x=20\n\tend=09\n".to_vec());
+    let long = std::iter::repeat("x").take(100).collect::<String>();
+    let encoded = String::from_utf8(quoted_printable(long.as_bytes())).unwrap();
+    assert!(encoded.lines().all(|line| line.len() <= 76));
+    assert_eq!(encoded.replace("=\n", ""), long);
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b""), b"".to_vec());
+    assert_eq!(base64(b"f"), b"Zg==\n".to_vec());
+    assert_eq!(base64(b"fo"), b"Zm8=\n".to_vec());
+    assert_eq!(base64(b"foo"), b"Zm9v\n".to_vec());
+    assert_eq!(base64(b"foobar"), b"Zm9vYmFy\n".to_vec());
+    let encoded = base64(&[0xff; 60]);
+    let lines: Vec<&[u8]> = encoded.split(|&b| b == b'\n').collect();
+    assert_eq!(lines[0].len(), 76);
+    assert_eq!(lines[1], b"////");
+}
+
+// Write the body of a mail after its headers, in the given Content-Transfer-Encoding: "7bit",
+// "8bit", "quoted-printable", or "base64". Without an encoding, declare the body as 8bit UTF-8 if
+// it contains any non-ASCII text, and leave a plain ASCII body without MIME headers.
+fn write_mail_body<W: IoWrite>(out: &mut W, body: &[u8], encoding: Option<&str>) -> Result<()> {
+    let ascii = is_ascii(body);
+    let encoding = match encoding {
+        Some("7bit") if !ascii => { return Err("Cannot use 7bit transfer encoding: mail contains non-ASCII text".into()); }
+        Some(encoding) => encoding,
+        None if ascii => "",
+        None => "8bit",
+    };
+    if !encoding.is_empty() {
+        try!(writeln!(out, "MIME-Version: 1.0"));
+        try!(writeln!(out, "Content-Type: text/plain; charset=UTF-8"));
+        try!(writeln!(out, "Content-Transfer-Encoding: {}", encoding));
+    }
+    try!(writeln!(out, ""));
+    match encoding {
+        "quoted-printable" => try!(out.write_all(&quoted_printable(body))),
+        "base64" => try!(out.write_all(&base64(body))),
+        _ => try!(out.write_all(body)),
+    }
+    Ok(())
+}
+
+// Add the angle brackets around a Message-Id given on the command line, if not present.
+fn message_id_brackets(message_id: &str) -> String {
+    format!("{}{}{}",
+            if message_id.starts_with('<') { "" } else { "<" },
+            message_id,
+            if message_id.ends_with('>') { "" } else { ">" })
+}
+
 fn ensure_nl(s: &str) -> &'static str {
     if !s.ends_with('\n') {
         "\n"
@@ -1433,60 +3417,288 @@ fn ensure_nl(s: &str) -> &'static str {
     }
 }
 
-fn format(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+// Find the (base, series) of a previous version of the patch series, given either a git-series
+// commit (such as "git-series/name~1") or the last commit of the previous version, which is then
+// assumed to share the current base.
+fn previous_version(repo: &Repository, rev: &str, current_base: Oid) -> Result<(Oid, Oid)> {
+    let commit = try!(try!(repo.revparse_single(rev)).peel(ObjectType::Commit));
+    let tree = try!(try!(repo.find_commit(commit.id())).tree());
+    let base = tree.get_name("base").map(|e| e.id());
+    let series = tree.get_name("series").map(|e| e.id());
+    match (base, series) {
+        (Some(base), Some(series)) => Ok((base, series)),
+        (None, Some(_)) => Err(format!("Previous version {} has no base set", rev).into()),
+        _ => Ok((current_base, commit.id())),
+    }
+}
+
+// Build the subject prefix for mail number n of total (with the cover letter as number 0), such as
+// "[PATCH 02/10] ". Without numbering, only include the subject_patch, if any.
+fn mail_subject_prefix(subject_patch: &str, n: usize, total: usize, numbered: bool) -> String {
+    if numbered {
+        let num_width = total.to_string().len();
+        format!("[{}{}{:0>num_width$}/{}] ", subject_patch, ensure_space(subject_patch), n, total, num_width=num_width)
+    } else if subject_patch.is_empty() {
+        "".to_string()
+    } else {
+        format!("[{}] ", subject_patch)
+    }
+}
+
+#[test]
+fn test_mail_subject_prefix() {
+    let tests = vec![
+        ("PATCH", 1, 1, false, "[PATCH] "),
+        ("PATCH", 1, 1, true, "[PATCH 1/1] "),
+        ("PATCH", 0, 3, true, "[PATCH 0/3] "),
+        ("PATCH v2", 2, 12, true, "[PATCH v2 02/12] "),
+        ("", 1, 1, false, ""),
+        ("", 1, 2, true, "[1/2] "),
+    ];
+    for (subject_patch, n, total, numbered, prefix) in tests {
+        assert_eq!(mail_subject_prefix(subject_patch, n, total, numbered), prefix.to_string());
+    }
+}
+
+// Quote a string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string(""), "\"\"");
+    assert_eq!(json_string("[PATCH 1/2] caf\u{e9}"), "\"[PATCH 1/2] caf\u{e9}\"");
+    assert_eq!(json_string("a \"b\" \\ c\n\t\u{1}"), "\"a \\\"b\\\" \\\\ c\\n\\t\\u0001\"");
+}
+
+fn format(out: &mut Output, repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     let config = try!(try!(repo.config()).snapshot());
     let to_stdout = m.is_present("stdout");
+    let mbox = m.value_of_os("mbox");
+    let single_output = to_stdout || mbox.is_some();
     let no_from = m.is_present("no-from");
+    let transfer_encoding = m.value_of("transfer-encoding");
 
-    let shead_commit = try!(peel_to_commit(try!(try!(repo.find_reference(SHEAD_REF)).resolve())));
+    let shead = try!(repo.find_reference(SHEAD_REF));
+    let shead_commit = try!(peel_to_commit(try!(shead.resolve())));
     let stree = try!(shead_commit.tree());
 
     let series = try!(stree.get_name("series").ok_or("Internal error: series did not contain \"series\""));
     let base = try!(stree.get_name("base").ok_or("Cannot format series; no base set.\nUse \"git series base\" to set base."));
+    let base_id = try!(resolve_base(repo, &stree, base.id(), series.id()));
+    let mut commits = try!(series_patches(repo, series.id(), base_id, m.is_present("allow-merges"), abbrev));
 
-    let mut revwalk = try!(repo.revwalk());
-    revwalk.set_sorting(git2::SORT_TOPOLOGICAL|git2::SORT_REVERSE);
-    try!(revwalk.push(series.id()));
-    try!(revwalk.hide(base.id()));
-    let mut commits: Vec<Commit> = try!(revwalk.map(|c| {
-        let id = try!(c);
-        let commit = try!(repo.find_commit(id));
-        if commit.parent_ids().count() > 1 {
-            return Err(format!("Error: cannot format merge commit as patch:\n{}", try!(commit_summarize(repo, id))).into());
+    let committer = try!(get_signature(&config, "COMMITTER"));
+    let committer_name = String::from_utf8_lossy(committer.name_bytes());
+    let committer_email = String::from_utf8_lossy(committer.email_bytes());
+    let date = match m.value_of("date") {
+        Some(date) => Some(try!(parse_date(date))),
+        None => None,
+    };
+    let message_id_ids: Vec<Oid> = std::iter::once(shead_commit.id()).chain(commits.iter().map(|c| c.id())).collect();
+    let message_id_suffix = message_id_suffix(&committer, date.as_ref(), &message_id_ids);
+    // The identity to send the mails as; patches by anyone else get an in-body "From:" line.
+    let sender = match m.value_of("from") {
+        Some(from) => {
+            let (name, email) = try!(parse_ident(from).map_err(|e| format!("{} for --from", e)));
+            format!("{} <{}>", name, email)
         }
-        Ok(commit)
-    }).collect::<Result<_>>());
-    if commits.is_empty() {
-        return Err("No patches to format; series and base identical.".into());
-    }
+        None => format!("{} <{}>", committer_name, committer_email),
+    };
+    let signoff_enabled = if m.is_present("signoff") {
+        true
+    } else if m.is_present("no-signoff") {
+        false
+    } else {
+        try!(notfound_to_none(config.get_bool("format.signOff"))).unwrap_or(false)
+    };
+    let signoff = if signoff_enabled {
+        Some(format!("Signed-off-by: {} <{}>", committer_name, committer_email))
+    } else {
+        None
+    };
 
-    let committer = try!(get_signature(&config, "COMMITTER"));
-    let committer_name = committer.name().unwrap();
-    let committer_email = committer.email().unwrap();
-    let message_id_suffix = format!("{}.git-series.{}", committer.when().seconds(), committer_email);
-
-    let cover_entry = stree.get_name("cover");
-    let mut in_reply_to_message_id = m.value_of("in-reply-to").map(|v| {
-        format!("{}{}{}",
-                if v.starts_with('<') { "" } else { "<" },
-                v,
-                if v.ends_with('>') { "" } else { ">" })
-    });
-
-    let version = m.value_of("reroll-count");
+    // With --cover-letter, write a cover letter even if the series has none, using the series
+    // name as the subject; with --no-cover-letter, omit the cover letter of the series.
+    let cover_letter = if m.is_present("cover-letter") {
+        Some(true)
+    } else if m.is_present("no-cover-letter") {
+        Some(false)
+    } else {
+        match try!(notfound_to_none(config.get_str("format.coverLetter"))) {
+            None | Some("auto") => None,
+            Some(v) => Some(try!(Config::parse_bool(v).map_err(|e| format!("Error parsing format.coverLetter: {}", e)))),
+        }
+    };
+    let cover_content = match (cover_letter, stree.get_name("cover")) {
+        (Some(false), _) => None,
+        (_, Some(entry)) => Some(String::from_utf8_lossy(try!(repo.find_blob(entry.id())).content()).into_owned()),
+        (Some(true), None) => Some(format!("{}\n", try!(shead_series_name(&shead)))),
+        (None, None) => None,
+    };
+    let range_diff = match m.value_of("range-diff") {
+        None => None,
+        Some(rev) => {
+            if cover_content.is_none() {
+                return Err("Cannot include a range-diff without a cover letter.\nUse \"git series cover\" to add one, or --cover-letter to generate one.".into());
+            }
+            Some((rev, try!(previous_version(repo, rev, base_id))))
+        }
+    };
+    let interdiff = match m.value_of("interdiff") {
+        None => None,
+        Some(rev) => {
+            if cover_content.is_none() {
+                return Err("Cannot include an interdiff without a cover letter.\nUse \"git series cover\" to add one, or --cover-letter to generate one.".into());
+            }
+            let (_, previous_series) = try!(previous_version(repo, rev, base_id));
+            let previous_tree = try!(try!(repo.find_commit(previous_series)).tree());
+            let series_tree = try!(try!(repo.find_commit(series.id())).tree());
+            Some((rev, try!(diff_trees(repo, Some(&previous_tree), Some(&series_tree)))))
+        }
+    };
+    let series_config = try!(SeriesConfig::read_tree(repo, &stree));
+    let mut in_reply_to_message_id = m.value_of("in-reply-to").map(message_id_brackets);
+
+    let version = m.value_of("reroll-count").or(series_config.get("reroll-count"));
     let subject_prefix = if m.is_present("rfc") {
         "RFC PATCH"
     } else {
-        m.value_of("subject-prefix").unwrap_or("PATCH")
+        match m.value_of("subject-prefix").or(series_config.get("subject-prefix")) {
+            Some(prefix) => prefix,
+            None => try!(notfound_to_none(config.get_str("format.subjectPrefix"))).unwrap_or("PATCH"),
+        }
     };
     let subject_patch = version.map_or(
             subject_prefix.to_string(),
             |n| format!("{}{}v{}", subject_prefix, ensure_space(&subject_prefix), n));
     let file_prefix = version.map_or("".to_string(), |n| format!("v{}-", n));
 
-    let num_width = commits.len().to_string().len();
+    let start_number = match m.value_of("start-number") {
+        None => 1,
+        Some(n) => try!(n.parse::<usize>().map_err(|e| format!("Invalid start number \"{}\": {}", n, e))),
+    };
+    if start_number == 0 {
+        return Err("Invalid start number 0: the cover letter uses 0".into());
+    }
+    // The total in each subject counts the patches before the start number too, as if from an
+    // earlier run of format for the same series.
+    let total = match m.value_of("total") {
+        None => start_number - 1 + commits.len(),
+        Some(n) => try!(n.parse::<usize>().map_err(|e| format!("Invalid total \"{}\": {}", n, e))),
+    };
+    if total < start_number - 1 + commits.len() {
+        return Err(format!("Total {} is less than the number of the last patch, {}", total, start_number - 1 + commits.len()).into());
+    }
+
+    // Like git format-patch, count the version prefix as part of the filename length.
+    let filename_max_length = match m.value_of("filename-max-length") {
+        Some(n) => try!(n.parse::<usize>().map_err(|e| format!("Invalid filename length \"{}\": {}", n, e))),
+        None => match try!(notfound_to_none(config.get_i32("format.filenameMaxLength"))) {
+            Some(n) if n < 0 => { return Err(format!("Invalid format.filenameMaxLength {}", n).into()); }
+            Some(n) => n as usize,
+            None => 64,
+        },
+    };
+    let patch_name = |n: usize, subject: &str| patch_filename(n, subject, filename_max_length.saturating_sub(file_prefix.len()));
+
+    // None means "auto": number the mails unless formatting a lone patch without a cover letter.
+    let numbered = if m.is_present("numbered") {
+        Some(true)
+    } else if m.is_present("no-numbered") {
+        Some(false)
+    } else {
+        match try!(notfound_to_none(config.get_str("format.numbered"))) {
+            None | Some("auto") => None,
+            Some(v) => Some(try!(Config::parse_bool(v).map_err(|e| format!("Error parsing format.numbered: {}", e)))),
+        }
+    };
+
+    // Compute the diff of each patch once, for the whitespace check, the diffstat, and the patch
+    // itself. With --allow-merges, a merge commit shows as its diff against its first parent.
+    let mut diffs = Vec::with_capacity(commits.len());
+    for commit in commits.iter() {
+        let parent = try!(commit.parent(0));
+        diffs.push(try!(diff_trees_for_apply(repo, Some(&try!(parent.tree())), Some(&try!(commit.tree())))));
+    }
+
+    // Check for whitespace errors before writing anything, so --whitespace=error leaves no partial
+    // output.
+    let whitespace = m.value_of("whitespace");
+    if whitespace == Some("warn") || whitespace == Some("error") {
+        let mut errors = Vec::new();
+        for (commit, diff) in commits.iter_mut().zip(diffs.iter()) {
+            let mut patch = Vec::new();
+            try!(write_diff(&mut patch, &DiffColors::plain(), diff, false));
+            let (commit_errors, _) = check_whitespace(&String::from_utf8_lossy(&patch));
+            if !commit_errors.is_empty() {
+                let summary = try!(commit_obj_summarize(commit, abbrev));
+                errors.extend(commit_errors.into_iter().map(|e| format!("{}: {}", summary, e)));
+            }
+        }
+        if !errors.is_empty() {
+            if whitespace == Some("error") {
+                return Err(format!("Whitespace errors in patch series:\n{}\nUse --whitespace=fix to fix them in the formatted patches.", errors.join("\n")).into());
+            }
+            let mut stderr = std::io::stderr();
+            for e in errors {
+                writeln!(stderr, "warning: {}", e).unwrap();
+            }
+        }
+    }
+
+    if m.is_present("dry-run") {
+        if cover_content.is_some() {
+            println!("{}{}", file_prefix, patch_name(0, "cover letter"));
+        }
+        for (commit_num, commit) in commits.iter().enumerate() {
+            let message = commit_message(commit);
+            let (subject, _) = split_message(&message);
+            println!("{}{}", file_prefix, patch_name(start_number + commit_num, subject));
+        }
+        return Ok(());
+    }
+
+    let signature = try!(mail_signature(&config, m));
 
-    let signature = mail_signature();
+    // With --base, record that upstream commit as the base commit, and identify the patches
+    // between it and the series base as prerequisites, like "git format-patch --base".
+    let (base_commit_id, prerequisite_patch_ids) = match m.value_of("base") {
+        None => (base_id, Vec::new()),
+        Some(upstream) => {
+            let upstream_id = try!(try!(repo.revparse_single(upstream)).peel(ObjectType::Commit)).id();
+            if !try!(is_ancestor(repo, upstream_id, base_id)) {
+                return Err(format!("Cannot use {} as the base commit: not an ancestor of the series base {}", upstream, base_id).into());
+            }
+            let mut ids = Vec::new();
+            for commit in try!(series_commits(repo, base_id, upstream_id, false, abbrev)) {
+                ids.push(try!(patch_id(repo, &commit)));
+            }
+            (upstream_id, ids)
+        }
+    };
+    let base_info = {
+        let mut info = format!("base-commit: {}\n", base_commit_id);
+        for id in prerequisite_patch_ids {
+            writeln!(info, "prerequisite-patch-id: {}", id).unwrap();
+        }
+        info
+    };
 
     if to_stdout {
         try!(out.auto_pager(&config, "format-patch", true));
@@ -1498,114 +3710,277 @@ fn format(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
     };
     let mut out : Box<IoWrite> = if to_stdout {
         Box::new(out)
+    } else if let Some(mbox) = mbox {
+        Box::new(try!(File::create(mbox)))
     } else {
         Box::new(std::io::stdout())
     };
-    let patch_file = |name: &str| -> Result<Box<IoWrite>> {
+    // With --send-email, write the patches to a temporary directory and hand them to git
+    // send-email afterward.
+    let send_email_dir = if m.is_present("send-email") {
+        Some(try!(TempDir::new("git-series")))
+    } else {
+        None
+    };
+    // Addresses on the command line replace those from the series config, which in turn replace
+    // those from format.to and format.cc in the git config.
+    let mut recipients = Vec::new();
+    for &(option, name) in [("--to", "to"), ("--cc", "cc")].iter() {
+        let addresses: Vec<OsString> = match m.values_of_os(name) {
+            Some(addresses) => addresses.map(OsString::from).collect(),
+            None if !series_config.get_all(name).is_empty() => series_config.get_all(name).into_iter().map(OsString::from).collect(),
+            None => try!(config_multivar(&config, &format!("format.{}", name))).into_iter().map(OsString::from).collect(),
+        };
+        recipients.push((option, addresses));
+    }
+    // Every mail goes to these addresses, in its "To:" and "Cc:" headers.
+    let header_addresses = |option: &str| -> Vec<String> {
+        recipients.iter().filter(|&&(o, _)| o == option)
+            .flat_map(|&(_, ref addresses)| addresses.iter().map(|a| mail_encode_ident(&a.to_string_lossy())))
+            .collect()
+    };
+    let (mail_to, mail_cc) = (header_addresses("--to"), header_addresses("--cc"));
+    let write_recipients = |out: &mut IoWrite, to: &[String], cc: &[String]| -> Result<()> {
+        if !to.is_empty() {
+            try!(writeln!(out, "{}", fold_header("To", &to.join(", "))));
+        }
+        if !cc.is_empty() {
+            try!(writeln!(out, "{}", fold_header("Cc", &cc.join(", "))));
+        }
+        Ok(())
+    };
+    // With --cc-from-trailers, each patch also goes to the addresses in its own trailers, other
+    // than those every mail goes to already.
+    let cc_from_trailers = m.is_present("cc-from-trailers");
+    let signoff_cc = m.is_present("signoff-cc");
+    let keep_subject = m.is_present("keep-subject");
+    // Each entry of the --manifest: the file holding the mail (none for --stdout), and its
+    // Message-Id, subject, In-Reply-To, and commit.
+    let mut manifest: Vec<(Option<String>, String, String, Option<String>, Oid)> = Vec::new();
+    let mail_filename = |name: &str| -> Option<String> {
+        if single_output {
+            mbox.map(|f| f.to_string_lossy().into_owned())
+        } else {
+            Some(format!("{}{}", file_prefix, name))
+        }
+    };
+    let recipient_addresses: std::collections::HashSet<String> = recipients.iter()
+        .flat_map(|&(_, ref addresses)| addresses.iter().map(|a| mail_address(&a.to_string_lossy())))
+        .collect();
+    // The progress line stays below the list of filenames, and disappears once done.
+    let show_progress = !single_output && verbosity > Verbosity::Quiet && isatty::stdout_isatty();
+    const CLEAR_LINE: &'static str = "\r\x1b[K";
+    let total_mails = commits.len() + if cover_content.is_some() { 1 } else { 0 };
+    let mut patch_filenames = Vec::new();
+    let mut patch_file = |name: &str| -> Result<Box<IoWrite>> {
         let name = format!("{}{}", file_prefix, name);
-        println!("{}", name);
-        Ok(Box::new(try!(File::create(name))))
+        let path = match send_email_dir {
+            Some(ref dir) => dir.path().join(&name),
+            None => {
+                if verbosity > Verbosity::Quiet {
+                    println!("{}{}", if show_progress { CLEAR_LINE } else { "" }, name);
+                }
+                PathBuf::from(name)
+            }
+        };
+        let file = try!(File::create(&path));
+        patch_filenames.push(path);
+        if show_progress {
+            print!("{}Writing patch {}/{}", CLEAR_LINE, patch_filenames.len(), total_mails);
+            try!(std::io::stdout().flush());
+        }
+        Ok(Box::new(file))
     };
 
-    if let Some(ref entry) = cover_entry {
-        let cover_blob = try!(repo.find_blob(entry.id()));
-        let content = try!(std::str::from_utf8(cover_blob.content())).to_string();
-        let (subject, body) = split_message(&content);
+    if let Some(ref content) = cover_content {
+        let (subject, body) = split_message(content);
+        // The cover letter gets its own sign-off, after the text written with "git series cover"
+        // and before the generated shortlog and diffstat.
+        let body = match signoff {
+            Some(ref signoff) => append_trailer(body, signoff),
+            None => body.to_string(),
+        };
 
-        let series_tree = try!(repo.find_commit(series.id())).tree().unwrap();
-        let base_tree = try!(repo.find_commit(base.id())).tree().unwrap();
-        let diff = try!(repo.diff_tree_to_tree(Some(&base_tree), Some(&series_tree), None));
+        let series_tree = try!(try!(repo.find_commit(series.id())).tree());
+        let base_tree = try!(try!(repo.find_commit(base_id)).tree());
+        let diff = try!(diff_trees(repo, Some(&base_tree), Some(&series_tree)));
         let stats = try!(diffstat(&diff));
 
-        if !to_stdout {
-            out = try!(patch_file("0000-cover-letter.patch"));
+        if !single_output {
+            out = try!(patch_file(&patch_name(0, "cover letter")));
         }
         try!(writeln!(out, "From {} Mon Sep 17 00:00:00 2001", shead_commit.id()));
         let cover_message_id = format!("<cover.{}.{}>", shead_commit.id(), message_id_suffix);
         try!(writeln!(out, "Message-Id: {}", cover_message_id));
         if let Some(ref message_id) = in_reply_to_message_id {
             try!(writeln!(out, "In-Reply-To: {}", message_id));
-            try!(writeln!(out, "References: {}", message_id));
+            try!(writeln!(out, "{}", fold_header("References", message_id)));
         }
+        let full_subject = format!("{}{}", mail_subject_prefix(&subject_patch, 0, total, numbered.unwrap_or(true)), subject);
+        manifest.push((mail_filename(&patch_name(0, "cover letter")), cover_message_id.clone(), full_subject.clone(), in_reply_to_message_id.clone(), shead_commit.id()));
         in_reply_to_message_id = Some(cover_message_id);
-        try!(writeln!(out, "From: {} <{}>", committer_name, committer_email));
-        try!(writeln!(out, "Date: {}", date_822(committer.when())));
-        try!(writeln!(out, "Subject: [{}{}{:0>num_width$}/{}] {}\n", subject_patch, ensure_space(&subject_patch), 0, commits.len(), subject, num_width=num_width));
+        try!(writeln!(out, "From: {}", mail_encode_ident(&sender)));
+        try!(writeln!(out, "Date: {}", date_822(date.unwrap_or(committer.when()))));
+        try!(writeln!(out, "{}", fold_header("Subject", &mail_encode_header(&full_subject))));
+        try!(write_recipients(&mut out, &mail_to, &mail_cc));
+        let mut mail = Vec::new();
         if !body.is_empty() {
-            try!(writeln!(out, "{}", body));
+            try!(writeln!(mail, "{}", body));
+        }
+        try!(writeln!(mail, "{}", shortlog(&mut commits)));
+        try!(writeln!(mail, "{}", stats));
+        if let Some((rev, previous)) = range_diff {
+            try!(writeln!(mail, "Range-diff against {}:", rev));
+            try!(write_commit_range_diff(&mut mail, repo, &DiffColors::plain(), previous, (base_id, series.id()), abbrev));
+            try!(writeln!(mail, ""));
+        }
+        if let Some((rev, ref diff)) = interdiff {
+            try!(writeln!(mail, "Interdiff against {}:", rev));
+            try!(write_diff(&mut mail, &DiffColors::plain(), diff, false));
+            try!(writeln!(mail, ""));
         }
-        try!(writeln!(out, "{}", shortlog(&mut commits)));
-        try!(writeln!(out, "{}", stats));
-        try!(writeln!(out, "base-commit: {}", base.id()));
-        try!(writeln!(out, "{}", signature));
+        try!(write!(mail, "{}", base_info));
+        if let Some(ref signature) = signature {
+            try!(writeln!(mail, "{}", signature));
+        }
+        try!(write_mail_body(&mut out, &mail, transfer_encoding));
     }
 
+    let mut whitespace_fixed = 0;
     for (commit_num, commit) in commits.iter().enumerate() {
-        let first_mail = commit_num == 0 && cover_entry.is_none();
-        if to_stdout && !first_mail {
+        let first_mail = commit_num == 0 && cover_content.is_none();
+        if single_output && !first_mail {
             try!(writeln!(out, ""));
         }
 
-        let message = commit.message().unwrap();
-        let (subject, body) = split_message(message);
+        let message = commit_message(commit);
+        let (mut subject, body) = split_message(&message);
+        if keep_subject {
+            // split_message trims trailing whitespace from the subject; use the first line as is.
+            subject = message.splitn(2, '\n').next().unwrap();
+        }
+        let trailers = message_trailers(body);
+        let body = match signoff {
+            Some(ref signoff) => append_trailer(body, signoff),
+            None => body.to_string(),
+        };
         let commit_id = commit.id();
         let commit_author = commit.author();
-        let commit_author_name = commit_author.name().unwrap();
-        let commit_author_email = commit_author.email().unwrap();
-        let summary_sanitized = sanitize_summary(&subject);
+        let commit_author_name = String::from_utf8_lossy(commit_author.name_bytes());
+        let commit_author_email = String::from_utf8_lossy(commit_author.email_bytes());
+        let commit_author_ident = format!("{} <{}>", commit_author_name, commit_author_email);
         let this_message_id = format!("<{}.{}>", commit_id, message_id_suffix);
-        let parent = try!(commit.parent(0));
-        let diff = try!(repo.diff_tree_to_tree(Some(&parent.tree().unwrap()), Some(&commit.tree().unwrap()), None));
-        let stats = try!(diffstat(&diff));
+        let diff = &diffs[commit_num];
+        let stats = try!(diffstat(diff));
 
-        if !to_stdout {
-            out = try!(patch_file(&format!("{:04}-{}.patch", commit_num+1, summary_sanitized)));
+        if !single_output {
+            out = try!(patch_file(&patch_name(start_number + commit_num, subject)));
         }
         try!(writeln!(out, "From {} Mon Sep 17 00:00:00 2001", commit_id));
         try!(writeln!(out, "Message-Id: {}", this_message_id));
         if let Some(ref message_id) = in_reply_to_message_id {
             try!(writeln!(out, "In-Reply-To: {}", message_id));
-            try!(writeln!(out, "References: {}", message_id));
+            try!(writeln!(out, "{}", fold_header("References", message_id)));
         }
+        // Like git format-patch, don't number a lone patch without a cover letter by default.
+        let numbered = numbered.unwrap_or(total > 1 || cover_content.is_some());
+        let prefix = mail_subject_prefix(&subject_patch, start_number + commit_num, total, numbered);
+        let full_subject = format!("{}{}", prefix, subject);
+        manifest.push((mail_filename(&patch_name(start_number + commit_num, subject)), this_message_id.clone(), full_subject.clone(), in_reply_to_message_id.clone(), commit_id));
         if first_mail {
             in_reply_to_message_id = Some(this_message_id);
         }
         if no_from {
-            try!(writeln!(out, "From: {} <{}>", commit_author_name, commit_author_email));
+            try!(writeln!(out, "From: {}", mail_encode_ident(&commit_author_ident)));
         } else {
-            try!(writeln!(out, "From: {} <{}>", committer_name, committer_email));
+            try!(writeln!(out, "From: {}", mail_encode_ident(&sender)));
         }
-        try!(writeln!(out, "Date: {}", date_822(commit_author.when())));
-        let prefix = if commits.len() == 1 && cover_entry.is_none() {
-            if subject_patch.is_empty() {
-                "".to_string()
-            } else {
-                format!("[{}] ", subject_patch)
+        try!(writeln!(out, "Date: {}", date_822(date.unwrap_or(commit_author.when()))));
+        try!(writeln!(out, "{}", fold_header("Subject", &mail_encode_header(&full_subject))));
+        let (mut to, mut cc) = (mail_to.clone(), mail_cc.clone());
+        if cc_from_trailers {
+            let mut seen = recipient_addresses.clone();
+            for &(key, value) in trailers.iter() {
+                let key = key.to_lowercase();
+                let list = if key == "to" {
+                    &mut to
+                } else if key == "cc" || (signoff_cc && key == "signed-off-by") {
+                    &mut cc
+                } else {
+                    continue;
+                };
+                if seen.insert(mail_address(value)) {
+                    list.push(mail_encode_ident(value));
+                }
             }
-        } else {
-            format!("[{}{}{:0>num_width$}/{}] ", subject_patch, ensure_space(&subject_patch), commit_num+1, commits.len(), num_width=num_width)
-        };
-        try!(writeln!(out, "Subject: {}{}\n", prefix, subject));
+        }
+        try!(write_recipients(&mut out, &to, &cc));
+        let mut mail = Vec::new();
 
-        if !no_from && (commit_author_name != committer_name || commit_author_email != committer_email) {
-            try!(writeln!(out, "From: {} <{}>\n", commit_author_name, commit_author_email));
+        if !no_from && commit_author_ident != sender {
+            try!(writeln!(mail, "From: {}\n", commit_author_ident));
         }
         if !body.is_empty() {
-            try!(write!(out, "{}{}", body, ensure_nl(&body)));
+            try!(write!(mail, "{}{}", body, ensure_nl(&body)));
+        }
+        try!(writeln!(mail, "---"));
+        try!(writeln!(mail, "{}", stats));
+        if whitespace == Some("fix") {
+            let mut patch = Vec::new();
+            try!(write_diff(&mut patch, &DiffColors::plain(), diff, false));
+            let (errors, fixed) = check_whitespace(&String::from_utf8_lossy(&patch));
+            whitespace_fixed += errors.len();
+            try!(mail.write_all(fixed.as_bytes()));
+        } else {
+            try!(write_diff(&mut mail, &diffcolors, diff, false));
         }
-        try!(writeln!(out, "---"));
-        try!(writeln!(out, "{}", stats));
-        try!(write_diff(&mut out, &diffcolors, &diff, false));
         if first_mail {
-            try!(writeln!(out, "\nbase-commit: {}", base.id()));
+            try!(write!(mail, "\n{}", base_info));
+        }
+        if let Some(ref signature) = signature {
+            try!(writeln!(mail, "{}", signature));
+        }
+        try!(write_mail_body(&mut out, &mail, transfer_encoding));
+    }
+    drop(out);
+    if show_progress {
+        print!("{}", CLEAR_LINE);
+        try!(std::io::stdout().flush());
+    }
+    if whitespace_fixed > 0 && verbosity > Verbosity::Quiet {
+        writeln!(std::io::stderr(), "Fixed {} whitespace error{}", whitespace_fixed, if whitespace_fixed == 1 { "" } else { "s" }).unwrap();
+    }
+
+    if let Some(manifest_path) = m.value_of_os("manifest") {
+        let json_opt = |s: &Option<String>| s.as_ref().map_or("null".to_string(), |s| json_string(s));
+        let mut manifest_file = try!(File::create(manifest_path));
+        try!(writeln!(manifest_file, "["));
+        for (n, &(ref filename, ref message_id, ref subject, ref in_reply_to, oid)) in manifest.iter().enumerate() {
+            try!(writeln!(manifest_file, "  {{\"filename\": {}, \"message_id\": {}, \"subject\": {}, \"in_reply_to\": {}, \"oid\": \"{}\"}}{}",
+                          json_opt(filename), json_string(message_id), json_string(subject), json_opt(in_reply_to), oid,
+                          if n + 1 < manifest.len() { "," } else { "" }));
+        }
+        try!(writeln!(manifest_file, "]"));
+    }
+
+    if send_email_dir.is_some() {
+        let mut cmd = Command::new("git");
+        cmd.arg("send-email");
+        for &(option, ref addresses) in recipients.iter() {
+            for address in addresses {
+                cmd.arg(option).arg(address);
+            }
+        }
+        let status = try!(cmd.args(&patch_filenames).status());
+        if !status.success() {
+            return Err(format!("git send-email exited with status {}", status).into());
         }
-        try!(writeln!(out, "{}", signature));
     }
 
     Ok(())
 }
 
-fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+fn log(out: &mut Output, repo: &Repository, m: &ArgMatches, abbrev: usize) -> Result<()> {
     let config = try!(try!(repo.config()).snapshot());
     try!(out.auto_pager(&config, "log", true));
     let diffcolors = try!(DiffColors::new(out, &config));
@@ -1634,6 +4009,7 @@ fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
     }
 
     let show_diff = m.is_present("patch");
+    let show_stat = m.is_present("stat");
 
     let mut first = true;
     for oid in revwalk {
@@ -1647,14 +4023,29 @@ fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
         let author = commit.author();
 
         try!(writeln!(out, "{}", diffcolors.commit.paint(format!("commit {}", oid))));
-        try!(writeln!(out, "Author: {} <{}>", author.name().unwrap(), author.email().unwrap()));
+        try!(writeln!(out, "Author: {} <{}>", String::from_utf8_lossy(author.name_bytes()), String::from_utf8_lossy(author.email_bytes())));
         try!(writeln!(out, "Date:   {}\n", date_822(author.when())));
-        for line in commit.message().unwrap().lines() {
+        for line in commit_message(&commit).lines() {
             try!(writeln!(out, "    {}", line));
         }
 
-        if show_diff {
-            let tree = try!(commit.tree());
+        let tree = try!(commit.tree());
+        let description_id = tree.get_name("description").map(|e| e.id());
+        let parent_description_id = match commit.parent_ids().next() {
+            Some(parent_id) if tree.get_id(parent_id).is_none() => try!(try!(repo.find_commit(parent_id)).tree()).get_name("description").map(|e| e.id()),
+            _ => None,
+        };
+        if description_id != parent_description_id {
+            try!(writeln!(out, "\n    Description:"));
+            match description_id {
+                Some(id) => for line in String::from_utf8_lossy(try!(repo.find_blob(id)).content()).lines() {
+                    try!(writeln!(out, "        {}", line));
+                },
+                None => try!(writeln!(out, "        (removed)")),
+            }
+        }
+
+        if show_diff || show_stat {
             let parent_ids: Vec<_> = commit.parent_ids().take_while(|parent_id| tree.get_id(*parent_id).is_none()).collect();
 
             try!(writeln!(out, ""));
@@ -1666,7 +4057,16 @@ fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
                 } else {
                     Some(try!(try!(repo.find_commit(parent_ids[0])).tree()))
                 };
-                try!(write_series_diff(out, repo, &diffcolors, parent_tree.as_ref(), Some(&tree)));
+                if show_stat {
+                    let diff = try!(diff_trees(repo, parent_tree.as_ref(), Some(&tree)));
+                    try!(write!(out, "{}", try!(diffstat(&diff))));
+                    if show_diff {
+                        try!(writeln!(out, ""));
+                    }
+                }
+                if show_diff {
+                    try!(write_series_diff(out, repo, &diffcolors, parent_tree.as_ref(), Some(&tree), abbrev));
+                }
             }
         }
     }
@@ -1674,10 +4074,54 @@ fn log(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
+fn reflog(out: &mut Output, repo: &Repository, m: &ArgMatches, abbrev: usize) -> Result<()> {
+    let series_name = match m.value_of("name") {
+        Some(name) => name.to_string(),
+        None => {
+            let shead = try!(notfound_to_none(repo.find_reference(SHEAD_REF)));
+            try!(shead_series_name(&try!(shead.ok_or("No current patch series; specify a series name."))))
+        }
+    };
+    if !try!(Internals::exists(repo, &series_name)) {
+        return Err(format!("Series {} does not exist.", series_name).into());
+    }
+    let limit = match m.value_of("n") {
+        None => None,
+        Some(n) => Some(try!(n.parse::<usize>().map_err(|e| format!("Invalid number of entries \"{}\": {}", n, e)))),
+    };
+    let prefixes = if m.is_present("internals") {
+        vec![SERIES_PREFIX, STAGED_PREFIX, WORKING_PREFIX]
+    } else {
+        vec![SERIES_PREFIX]
+    };
+
+    let config = try!(try!(repo.config()).snapshot());
+    try!(out.auto_pager(&config, "reflog", true));
+    let color_commit = try!(out.get_color(&config, "diff", "commit", "yellow"));
+    for prefix in prefixes {
+        let refname = format!("{}{}", prefix, series_name);
+        if try!(notfound_to_none(repo.refname_to_id(&refname))).is_none() {
+            continue;
+        }
+        let reflog = try!(repo.reflog(&refname));
+        for (i, entry) in reflog.iter().enumerate().take(limit.unwrap_or(reflog.len())) {
+            let short_id = try!(short_id(&try!(repo.find_object(entry.id_new(), None)), abbrev));
+            let message = entry.message_bytes().map_or(String::new(), |m| String::from_utf8_lossy(m).into_owned());
+            try!(writeln!(out, "{} {}@{{{}}}: {}", color_commit.paint(short_id), refname, i, message));
+        }
+    }
+    Ok(())
+}
+
+// Check for the marker "git series rebase" leaves in the rebase state directory.
+fn series_rebase_in_progress(repo: &Repository) -> bool {
+    repo.path().join("rebase-merge").join("git-series").exists()
+}
+
+fn rebase(repo: &Repository, m: &ArgMatches, verbosity: Verbosity, abbrev: usize) -> Result<()> {
     match repo.state() {
         git2::RepositoryState::Clean => (),
-        git2::RepositoryState::RebaseMerge if repo.path().join("rebase-merge").join("git-series").exists() => {
+        git2::RepositoryState::RebaseMerge if series_rebase_in_progress(repo) => {
             return Err("git series rebase already in progress.\nUse \"git rebase --continue\" or \"git rebase --abort\".".into());
         },
         s => { return Err(format!("{:?} in progress; cannot rebase", s).into()); }
@@ -1685,12 +4129,27 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
 
     let internals = try!(Internals::read(repo));
     let series = try!(try!(internals.working.get("series")).ok_or("Could not find entry \"series\" in working index"));
-    let base = try!(try!(internals.working.get("base")).ok_or("Cannot rebase series; no base set.\nUse \"git series base\" to set base."));
-    if series.id() == base.id() {
-        return Err("No patches to rebase; series and base identical.".into());
-    } else if !try!(repo.graph_descendant_of(series.id(), base.id())) {
-        return Err(format!("Cannot rebase: current base {} not an ancestor of series {}", base.id(), series.id()).into());
-    }
+    // With --onto, the positional argument names the upstream: like "git rebase --onto <newbase>
+    // <upstream>", rebase the commits after the upstream, regardless of the base of the series.
+    // With --root, like "git rebase --root", rebase every commit back to the root of history.
+    let (base_id, commits) = if m.is_present("root") {
+        (None, try!(walk_commits(repo, series.id(), None, false, abbrev)))
+    } else {
+        let (base_id, base_desc) = match m.value_of("newbase") {
+            Some(_) => {
+                let upstream = m.value_of("onto").unwrap();
+                (try!(resolve_commit(repo, upstream).map_err(|e| format!("Cannot rebase from upstream {}: {}", upstream, e))), "upstream")
+            },
+            None => {
+                let base = try!(try!(internals.working.get("base")).ok_or("Cannot rebase series; no base set.\nUse \"git series base\" to set base, or \"git series rebase --root\" to rebase the whole history."));
+                (base.id(), "current base")
+            },
+        };
+        if !try!(is_ancestor(repo, base_id, series.id())) {
+            return Err(format!("Cannot rebase: {} {} not an ancestor of series {}", base_desc, base_id, series.id()).into());
+        }
+        (Some(base_id), try!(series_patches(repo, series.id(), base_id, false, abbrev)))
+    };
 
     // Check for unstaged or uncommitted changes before attempting to rebase.
     let series_commit = try!(repo.find_commit(series.id()));
@@ -1710,38 +4169,63 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
         return Err(unclean.into());
     }
 
-    let mut revwalk = try!(repo.revwalk());
-    revwalk.set_sorting(git2::SORT_TOPOLOGICAL|git2::SORT_REVERSE);
-    try!(revwalk.push(series.id()));
-    try!(revwalk.hide(base.id()));
-    let commits: Vec<Commit> = try!(revwalk.map(|c| {
-        let id = try!(c);
-        let mut commit = try!(repo.find_commit(id));
-        if commit.parent_ids().count() > 1 {
-            return Err(format!("Error: cannot rebase merge commit:\n{}", try!(commit_obj_summarize(&mut commit))).into());
-        }
-        Ok(commit)
-    }).collect::<Result<_>>());
-
     let interactive = m.is_present("interactive");
-    let onto = match m.value_of("onto") {
+    let edit_cover = m.is_present("edit-cover");
+    let onto = match m.value_of("newbase").or(m.value_of("onto")) {
         None => None,
-        Some(onto) => {
-            let obj = try!(repo.revparse_single(onto));
-            let commit = try!(obj.peel(ObjectType::Commit));
-            Some(commit.id())
-        },
+        Some(onto) => Some(try!(resolve_commit(repo, onto).map_err(|e| format!("Cannot rebase onto {}: {}", onto, e)))),
     };
 
-    let newbase = onto.unwrap_or(base.id());
-    if newbase == base.id() && !interactive {
-        println!("Nothing to do: base unchanged and not rebasing interactively");
+    // Like "git rebase --root" without a commit to rebase onto, rebase onto an empty commit with
+    // no parents; git records it as "squash-onto", and turns the commits picked onto it into new
+    // root commits.
+    let squash_onto = if base_id.is_none() && onto.is_none() {
+        let config = try!(repo.config());
+        let committer = try!(get_signature(&config, "COMMITTER"));
+        let empty_tree = try!(repo.find_tree(try!(try!(repo.treebuilder(None)).write())));
+        Some(try!(repo.commit(None, &committer, &committer, "", &empty_tree, &[])))
+    } else {
+        None
+    };
+
+    // Without an explicit commit to rebase onto, a series tracking a base ref rebases onto its tip.
+    let tracked = if onto.is_none() && squash_onto.is_none() {
+        try!(tracked_base_tip(repo, try!(internals.working.get("base-ref"))))
+    } else {
+        None
+    };
+    // base_id is only unset with --root, which either has onto or rebases onto squash_onto.
+    let mut newbase = onto.or(squash_onto).or(tracked.as_ref().map(|&(_, tip)| tip)).or(base_id).unwrap();
+    // Like "git rebase --keep-base", rebase onto the fork point from the upstream rather than
+    // onto its tip.
+    if m.is_present("keep-base") {
+        if onto.is_none() && tracked.is_none() {
+            return Err("Cannot rebase with --keep-base: no upstream.\nSpecify a commit to rebase onto, or use \"git series base --track\" to track one.".into());
+        }
+        newbase = try!(repo.merge_base(base_id.unwrap(), newbase));
+    }
+    if Some(newbase) == base_id && !interactive {
+        if verbosity > Verbosity::Quiet {
+            println!("Nothing to do: base unchanged and not rebasing interactively");
+        }
         return Ok(());
     }
 
-    let (base_short, _) = try!(commit_summarize_components(&repo, base.id()));
-    let (newbase_short, _) = try!(commit_summarize_components(&repo, newbase));
-    let (series_short, _) = try!(commit_summarize_components(&repo, series.id()));
+    let (newbase_short, newbase_summary) = try!(commit_summarize_components(&repo, newbase, abbrev));
+    let (series_short, _) = try!(commit_summarize_components(&repo, series.id(), abbrev));
+    let range = match base_id {
+        Some(base_id) => format!("{}..{}", try!(commit_summarize_components(&repo, base_id, abbrev)).0, series_short),
+        None => series_short,
+    };
+    if verbosity > Verbosity::Quiet {
+        let series_name = try!(shead_series_name(&try!(repo.find_reference(SHEAD_REF))));
+        let what = if base_id.is_some() { format!("base {}", range) } else { format!("{} back to the root", range) };
+        if squash_onto.is_some() {
+            println!("Rebasing series {} ({}) onto a new root", series_name, what);
+        } else {
+            println!("Rebasing series {} ({}) onto {} ({})", series_name, what, newbase_short, newbase_summary);
+        }
+    }
 
     let newbase_obj = try!(repo.find_commit(newbase)).into_object();
 
@@ -1763,15 +4247,39 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
     let mut orig_head_file = try!(create.open(dir.path().join("orig-head")));
     try!(writeln!(orig_head_file, "{}", series.id()));
 
+    if let Some(squash_onto) = squash_onto {
+        let mut squash_onto_file = try!(create.open(dir.path().join("squash-onto")));
+        try!(writeln!(squash_onto_file, "{}", squash_onto));
+    }
+
     let git_rebase_todo_filename = dir.path().join("git-rebase-todo");
     let mut git_rebase_todo = try!(create.open(&git_rebase_todo_filename));
     for mut commit in commits {
-        try!(writeln!(git_rebase_todo, "pick {}", try!(commit_obj_summarize(&mut commit))));
+        try!(writeln!(git_rebase_todo, "pick {}", try!(commit_obj_summarize(&mut commit, abbrev))));
     }
-    if let Some(onto) = onto {
-        try!(writeln!(git_rebase_todo, "exec git series base {}", onto));
+    // Steps that finish the rebase; these always run last, even if the user removes or reorders
+    // them while editing the todo.
+    let mut finish = Vec::new();
+    if onto.is_some() {
+        finish.push(format!("exec git series base {}", newbase));
+    } else if squash_onto.is_some() {
+        finish.push("exec git series base --delete".to_string());
+    } else if let Some((ref name, _)) = tracked {
+        finish.push(format!("exec git series base --track {}", name));
+    }
+    // Run the cover letter editor as the last step, so it runs even if the rebase stops and the
+    // user continues it later.
+    if edit_cover {
+        finish.push("exec git series cover".to_string());
+    }
+    for line in finish.iter() {
+        try!(writeln!(git_rebase_todo, "{}", line));
+    }
+    if squash_onto.is_some() {
+        try!(writeln!(git_rebase_todo, "\n# Rebase {} onto a new root", range));
+    } else {
+        try!(writeln!(git_rebase_todo, "\n# Rebase {} onto {}", range, newbase_short));
     }
-    try!(writeln!(git_rebase_todo, "\n# Rebase {}..{} onto {}", base_short, series_short, newbase_short));
     try!(write!(git_rebase_todo, "{}", REBASE_COMMENT));
     drop(git_rebase_todo);
 
@@ -1779,21 +4287,39 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
     if interactive {
         let config = try!(repo.config());
         try!(run_editor(&config, &git_rebase_todo_filename));
-        let mut file = try!(File::open(&git_rebase_todo_filename));
-        let mut todo = String::new();
-        try!(file.read_to_string(&mut todo));
-        let todo = try!(git2::message_prettify(todo, git2::DEFAULT_COMMENT_CHAR));
-        if todo.is_empty() {
+        let todo = try!(read_edited_file(&git_rebase_todo_filename));
+        if try!(git2::message_prettify(&todo[..], git2::DEFAULT_COMMENT_CHAR)).is_empty() {
             return Err("Nothing to do".into());
         }
+        if !finish.is_empty() {
+            let mut new_todo = String::new();
+            for line in todo.lines().filter(|line| !finish.iter().any(|f| line.trim() == f)) {
+                writeln!(new_todo, "{}", line).unwrap();
+            }
+            for line in finish.iter() {
+                writeln!(new_todo, "{}", line).unwrap();
+            }
+            try!(try!(File::create(&git_rebase_todo_filename)).write_all(new_todo.as_bytes()));
+        }
     }
 
-    // Avoid races by not calling .into_path until after the rename succeeds.
-    try!(std::fs::rename(dir.path(), final_path));
+    // Avoid races by not calling .into_path until after the rename succeeds. Until then, any
+    // error drops the TempDir and removes it; the rename itself either moves the complete
+    // directory into place or fails without creating rebase-merge.
+    try!(std::fs::rename(dir.path(), &final_path));
     dir.into_path();
 
-    try!(checkout_tree(repo, &newbase_obj));
-    try!(repo.reference("HEAD", newbase, true, &format!("rebase -i (start): checkout {}", newbase)));
+    // If the rebase fails to start, remove rebase-merge again, so that it doesn't look like a
+    // rebase in progress and block the next attempt.
+    let start = || -> Result<()> {
+        try!(checkout_tree(repo, &newbase_obj, false, verbosity));
+        try!(repo.reference("HEAD", newbase, true, &format!("rebase -i (start): checkout {}", newbase)));
+        Ok(())
+    }();
+    if let Err(e) = start {
+        let _ = std::fs::remove_dir_all(&final_path);
+        return Err(e);
+    }
 
     let status = try!(Command::new("git").arg("rebase").arg("--continue").status());
     if !status.success() {
@@ -1803,7 +4329,38 @@ fn rebase(repo: &Repository, m: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn req(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
+// Resolve a revision given on the command line to a commit, with a clear error if it names a tree
+// or blob instead.
+fn resolve_commit(repo: &Repository, rev: &str) -> Result<Oid> {
+    let obj = try!(repo.revparse_single(rev));
+    match obj.peel(ObjectType::Commit) {
+        Ok(commit) => Ok(commit.id()),
+        Err(_) => Err(format!("{} is a {}, not a commit", obj.id(), obj.kind().map_or("object", |k| k.str())).into()),
+    }
+}
+
+// Find the URL of the remote that "git push" would push to by default: remote.pushDefault, or else
+// "origin".
+fn default_push_url(config: &Config) -> Result<Option<String>> {
+    let remote = try!(notfound_to_none(config.get_string("remote.pushDefault"))).unwrap_or("origin".to_string());
+    match try!(notfound_to_none(config.get_string(&format!("remote.{}.pushurl", remote)))) {
+        Some(url) => Ok(Some(url)),
+        None => notfound_to_none(config.get_string(&format!("remote.{}.url", remote))),
+    }
+}
+
+// Connect to a remote and list its refs, once, for everything req needs to know about the remote.
+fn list_remote_refs(repo: &Repository, url: &str) -> Result<HashMap<String, Oid>> {
+    let mut remote = try!(repo.remote_anonymous(url));
+    try!(remote.connect(git2::Direction::Fetch).map_err(|e| format!("Could not connect to remote repository {}\n{}", url, e)));
+    let mut refs = HashMap::new();
+    for head in try!(remote.list()) {
+        refs.insert(head.name().to_string(), head.oid());
+    }
+    Ok(refs)
+}
+
+fn req(out: &mut Output, repo: &Repository, m: &ArgMatches, abbrev: usize) -> Result<()> {
     let config = try!(try!(repo.config()).snapshot());
     let shead = try!(repo.find_reference(SHEAD_REF));
     let shead_commit = try!(peel_to_commit(try!(shead.resolve())));
@@ -1813,39 +4370,40 @@ fn req(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
     let series_id = series.id();
     let mut series_commit = try!(repo.find_commit(series_id));
     let base = try!(stree.get_name("base").ok_or("Cannot request pull; no base set.\nUse \"git series base\" to set base."));
-    let mut base_commit = try!(repo.find_commit(base.id()));
+    let base_id = try!(resolve_base(repo, &stree, base.id(), series_id));
+    let mut base_commit = try!(repo.find_commit(base_id));
 
     let (cover_content, subject, cover_body) = if let Some(entry) = stree.get_name("cover") {
         let cover_blob = try!(repo.find_blob(entry.id()));
-        let content = try!(std::str::from_utf8(cover_blob.content())).to_string();
+        let content = String::from_utf8_lossy(cover_blob.content());
         let (subject, body) = split_message(&content);
         (Some(content.to_string()), subject.to_string(), Some(body.to_string()))
     } else {
         (None, try!(shead_series_name(&shead)), None)
     };
 
-    let url = m.value_of("url").unwrap();
-    let tag = m.value_of("tag").unwrap();
+    let series_config = try!(SeriesConfig::read_tree(repo, &stree));
+    // The URL defaults to the series config "url", or else the push URL of the default push
+    // remote; the tag defaults to the series config "tag", or else the name of the series.
+    let url = match m.value_of("url").map(String::from).or(series_config.get("url").map(String::from)) {
+        Some(url) => url,
+        None => try!(try!(default_push_url(&config)).ok_or("No repository URL given, series config \"url\" not set, and no remote to push to")),
+    };
+    let tag = match m.value_of("tag").or(series_config.get("tag")) {
+        Some(tag) => tag.to_string(),
+        None => try!(shead_series_name(&shead)),
+    };
+    let url = &url[..];
+    let tag = &tag[..];
     let full_tag = format!("refs/tags/{}", tag);
     let full_tag_peeled = format!("{}^{{}}", full_tag);
     let full_head = format!("refs/heads/{}", tag);
-    let mut remote = try!(repo.remote_anonymous(url));
-    try!(remote.connect(git2::Direction::Fetch).map_err(|e| format!("Could not connect to remote repository {}\n{}", url, e)));
-    let remote_heads = try!(remote.list());
+    let remote_refs = try!(list_remote_refs(repo, url));
 
     /* Find the requested name as either a tag or head */
-    let mut opt_remote_tag = None;
-    let mut opt_remote_tag_peeled = None;
-    let mut opt_remote_head = None;
-    for h in remote_heads {
-        if h.name() == full_tag {
-            opt_remote_tag = Some(h.oid());
-        } else if h.name() == full_tag_peeled {
-            opt_remote_tag_peeled = Some(h.oid());
-        } else if h.name() == full_head {
-            opt_remote_head = Some(h.oid());
-        }
-    }
+    let opt_remote_tag = remote_refs.get(&full_tag).cloned();
+    let opt_remote_tag_peeled = remote_refs.get(&full_tag_peeled).cloned();
+    let opt_remote_head = remote_refs.get(&full_head).cloned();
     let (msg, extra_body, remote_pull_name) = match (opt_remote_tag, opt_remote_tag_peeled, opt_remote_head) {
         (Some(remote_tag), Some(remote_tag_peeled), _) => {
             if remote_tag_peeled != series_id {
@@ -1853,7 +4411,7 @@ fn req(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
             }
             let local_tag = try!(repo.find_tag(remote_tag).map_err(|e|
                     format!("Could not find remote tag {} ({}) in local repository: {}", tag, remote_tag, e)));
-            let mut local_tag_msg = local_tag.message().unwrap().to_string();
+            let mut local_tag_msg = decode_message(local_tag.message_bytes().unwrap_or(b""), None);
             if let Some(sig_index) = local_tag_msg.find("-----BEGIN PGP ") {
                 local_tag_msg.truncate(sig_index);
             }
@@ -1879,168 +4437,517 @@ fn req(out: &mut Output, repo: &Repository, m: &ArgMatches) -> Result<()> {
             return Err(format!("Remote does not have either a tag or branch named {}", tag).into())
         }
     };
+    let msg = if m.is_present("signoff") {
+        let committer = try!(get_signature(&config, "COMMITTER"));
+        let signoff = format!("Signed-off-by: {} <{}>", String::from_utf8_lossy(committer.name_bytes()), String::from_utf8_lossy(committer.email_bytes()));
+        Some(append_trailer(msg.as_ref().map_or("", |s| &s[..]), &signoff))
+    } else {
+        msg
+    };
 
     let commit_subject_date = |commit: &mut Commit| -> String {
         let date = date_822(commit.author().when());
-        let summary = commit.summary().unwrap();
+        let summary = commit_summary(commit);
         format!("  {} ({})", summary, date)
     };
 
-    let mut revwalk = try!(repo.revwalk());
-    revwalk.set_sorting(git2::SORT_TOPOLOGICAL|git2::SORT_REVERSE);
-    try!(revwalk.push(series_id));
-    try!(revwalk.hide(base.id()));
-    let mut commits: Vec<Commit> = try!(revwalk.map(|c| {
-        Ok(try!(repo.find_commit(try!(c))))
-    }).collect::<Result<_>>());
-    if commits.is_empty() {
-        return Err("No patches to request pull of; series and base identical.".into());
-    }
+    let mut commits = try!(series_patches(repo, series_id, base_id, true, abbrev));
 
     let author = try!(get_signature(&config, "AUTHOR"));
-    let author_email = author.email().unwrap();
-    let message_id = format!("<pull.{}.{}.git-series.{}>", shead_commit.id(), author.when().seconds(), author_email);
+    let author_email = String::from_utf8_lossy(author.email_bytes());
+    let message_id = format!("<pull.{}.{}>", shead_commit.id(), message_id_suffix(&author, None, &[]));
 
-    let diff = try!(repo.diff_tree_to_tree(Some(&base_commit.tree().unwrap()), Some(&series_commit.tree().unwrap()), None));
+    let diff = try!(diff_trees(repo, Some(&try!(base_commit.tree())), Some(&try!(series_commit.tree()))));
     let stats = try!(diffstat(&diff));
 
-    try!(out.auto_pager(&config, "request-pull", true));
-    let diffcolors = try!(DiffColors::new(out, &config));
+    // Don't start the pager or color the patch if the mail will go through the editor first.
+    let compose = m.is_present("compose");
+    let diffcolors = if m.is_present("output") || compose {
+        DiffColors::plain()
+    } else {
+        try!(out.auto_pager(&config, "request-pull", true));
+        try!(DiffColors::new(out, &config))
+    };
 
-    try!(writeln!(out, "From {} Mon Sep 17 00:00:00 2001", shead_commit.id()));
-    try!(writeln!(out, "Message-Id: {}", message_id));
-    try!(writeln!(out, "From: {} <{}>", author.name().unwrap(), author_email));
-    try!(writeln!(out, "Date: {}", date_822(author.when())));
-    try!(writeln!(out, "Subject: [GIT PULL] {}\n", subject));
+    let mut mail = Vec::new();
     if let Some(extra_body) = extra_body {
-        try!(writeln!(out, "{}", extra_body));
-    }
-    try!(writeln!(out, "The following changes since commit {}:\n", base.id()));
-    try!(writeln!(out, "{}\n", commit_subject_date(&mut base_commit)));
-    try!(writeln!(out, "are available in the git repository at:\n"));
-    try!(writeln!(out, "  {} {}\n", url, remote_pull_name));
-    try!(writeln!(out, "for you to fetch changes up to {}:\n", series.id()));
-    try!(writeln!(out, "{}\n", commit_subject_date(&mut series_commit)));
-    try!(writeln!(out, "----------------------------------------------------------------"));
+        try!(writeln!(mail, "{}", extra_body));
+    }
+    try!(writeln!(mail, "The following changes since commit {}:\n", base_id));
+    try!(writeln!(mail, "{}\n", commit_subject_date(&mut base_commit)));
+    try!(writeln!(mail, "are available in the git repository at:\n"));
+    try!(writeln!(mail, "  {} {}\n", m.value_of("pull-url").unwrap_or(url), remote_pull_name));
+    try!(writeln!(mail, "for you to fetch changes up to {}:\n", series.id()));
+    try!(writeln!(mail, "{}\n", commit_subject_date(&mut series_commit)));
+    try!(writeln!(mail, "----------------------------------------------------------------"));
     if let Some(msg) = msg {
-        try!(writeln!(out, "{}", msg));
-        try!(writeln!(out, "----------------------------------------------------------------"));
+        try!(writeln!(mail, "{}", msg));
+        try!(writeln!(mail, "----------------------------------------------------------------"));
     }
-    try!(writeln!(out, "{}", shortlog(&mut commits)));
-    try!(writeln!(out, "{}", stats));
+    try!(writeln!(mail, "{}", shortlog(&mut commits)));
+    try!(writeln!(mail, "{}", stats));
     if m.is_present("patch") {
-        try!(write_diff(out, &diffcolors, &diff, false));
+        try!(write_diff(&mut mail, &diffcolors, &diff, false));
+    }
+    if let Some(signature) = try!(mail_signature(&config, m)) {
+        try!(writeln!(mail, "{}", signature));
+    }
+
+    if compose {
+        let filename = repo.path().join("PULLREQ_EDITMSG");
+        try!(try!(File::create(&filename)).write_all(&mail));
+        try!(run_editor(&config, &filename));
+        let msg = try!(read_edited_file(&filename));
+        if msg.trim().is_empty() {
+            return Err("Empty pull request message; aborting.".into());
+        }
+        mail = msg.into_bytes();
     }
-    try!(writeln!(out, "{}", mail_signature()));
+
+    let mut out: Box<IoWrite> = match m.value_of_os("output") {
+        Some(filename) => Box::new(try!(File::create(filename))),
+        None => Box::new(out),
+    };
+    try!(writeln!(out, "From {} Mon Sep 17 00:00:00 2001", shead_commit.id()));
+    try!(writeln!(out, "Message-Id: {}", message_id));
+    if let Some(in_reply_to) = m.value_of("in-reply-to").map(message_id_brackets) {
+        try!(writeln!(out, "In-Reply-To: {}", in_reply_to));
+        try!(writeln!(out, "{}", fold_header("References", &in_reply_to)));
+    }
+    try!(writeln!(out, "From: {}", mail_encode_ident(&format!("{} <{}>", String::from_utf8_lossy(author.name_bytes()), author_email))));
+    try!(writeln!(out, "Date: {}", date_822(author.when())));
+    try!(writeln!(out, "{}", fold_header("Subject", &mail_encode_header(&format!("[GIT PULL] {}", subject)))));
+    try!(write_mail_body(&mut out, &mail, None));
 
     Ok(())
 }
 
-fn main() {
-    let m = App::new("git-series")
-            .bin_name("git series")
-            .about("Track patch series in git")
-            .author("Josh Triplett <josh@joshtriplett.org>")
-            .version(crate_version!())
-            .global_setting(AppSettings::ColoredHelp)
-            .global_setting(AppSettings::UnifiedHelpMessage)
-            .global_setting(AppSettings::VersionlessSubcommands)
-            .subcommands(vec![
-                SubCommand::with_name("add")
-                    .about("Add changes to the index for the next series commit")
-                    .arg_from_usage("<change>... 'Changes to add (\"series\", \"base\", \"cover\")'"),
-                SubCommand::with_name("base")
-                    .about("Get or set the base commit for the patch series")
-                    .arg(Arg::with_name("base").help("Base commit").conflicts_with("delete"))
-                    .arg_from_usage("-d, --delete 'Clear patch series base'"),
-                SubCommand::with_name("checkout")
-                    .about("Resume work on a patch series; check out the current version")
-                    .arg_from_usage("<name> 'Patch series to check out'"),
-                SubCommand::with_name("commit")
-                    .about("Record changes to the patch series")
-                    .arg_from_usage("-a, --all 'Commit all changes'")
-                    .arg_from_usage("-m [msg] 'Commit message'")
-                    .arg_from_usage("-v, --verbose 'Show diff when preparing commit message'"),
-                SubCommand::with_name("cover")
-                    .about("Create or edit the cover letter for the patch series")
-                    .arg_from_usage("-d, --delete 'Delete cover letter'"),
-                SubCommand::with_name("cp")
-                    .about("Copy a patch series")
-                    .arg(Arg::with_name("source_dest").required(true).min_values(1).max_values(2).help("source (default: current series) and destination (required)")),
-                SubCommand::with_name("delete")
-                    .about("Delete a patch series")
-                    .arg_from_usage("<name> 'Patch series to delete'"),
-                SubCommand::with_name("detach")
-                    .about("Stop working on any patch series"),
-                SubCommand::with_name("diff")
-                    .about("Show changes in the patch series"),
-                SubCommand::with_name("format")
-                    .about("Prepare patch series for email")
-                    .arg_from_usage("--in-reply-to [Message-Id] 'Make the first mail a reply to the specified Message-Id'")
-                    .arg_from_usage("--no-from 'Don't include in-body \"From:\" headers when formatting patches authored by others'")
-                    .arg_from_usage("-v, --reroll-count=[N] 'Mark the patch series as PATCH vN'")
-                    .arg(Arg::from_usage("--rfc 'Use [RFC PATCH] instead of the standard [PATCH] prefix'").conflicts_with("subject-prefix"))
-                    .arg_from_usage("--stdout 'Write patches to stdout rather than files'")
-                    .arg_from_usage("--subject-prefix [Subject-Prefix] 'Use [Subject-Prefix] instead of the standard [PATCH] prefix'"),
-                SubCommand::with_name("log")
-                    .about("Show the history of the patch series")
-                    .arg_from_usage("-p, --patch 'Include a patch for each change committed to the series'"),
-                SubCommand::with_name("mv")
-                    .about("Move (rename) a patch series")
-                    .visible_alias("rename")
-                    .arg(Arg::with_name("source_dest").required(true).min_values(1).max_values(2).help("source (default: current series) and destination (required)")),
-                SubCommand::with_name("rebase")
-                    .about("Rebase the patch series")
-                    .arg_from_usage("[onto] 'Commit to rebase onto'")
-                    .arg_from_usage("-i, --interactive 'Interactively edit the list of commits'")
-                    .group(ArgGroup::with_name("action").args(&["onto", "interactive"]).multiple(true).required(true)),
-                SubCommand::with_name("req")
-                    .about("Generate a mail requesting a pull of the patch series")
-                    .visible_aliases(&["pull-request", "request-pull"])
-                    .arg_from_usage("-p, --patch 'Include patch in the mail'")
-                    .arg_from_usage("<url> 'Repository URL to request pull of'")
-                    .arg_from_usage("<tag> 'Tag or branch name to request pull of'"),
-                SubCommand::with_name("status")
-                    .about("Show the status of the patch series"),
-                SubCommand::with_name("start")
-                    .about("Start a new patch series")
-                    .arg_from_usage("<name> 'Patch series name'"),
-                SubCommand::with_name("unadd")
-                    .about("Undo \"git series add\", removing changes from the next series commit")
-                    .arg_from_usage("<change>... 'Changes to remove (\"series\", \"base\", \"cover\")'"),
-            ]).get_matches();
+// Find the repository like git does: use $GIT_DIR if set, such as in a hook, or else search from
+// the current directory; and use $GIT_WORK_TREE, if set, as the working tree.
+fn discover_repo() -> Result<Repository> {
+    // libgit2 doesn't support linked working trees, so it can't honor $GIT_COMMON_DIR; refuse
+    // rather than use the wrong refs.
+    if env::var_os("GIT_COMMON_DIR").is_some() {
+        return Err("fatal: GIT_COMMON_DIR is not supported".into());
+    }
+    let repo = match env::var_os("GIT_DIR") {
+        Some(git_dir) => try!(Repository::open(&git_dir).map_err(|e|
+                format!("fatal: not a git repository: '{}': {}", Path::new(&git_dir).display(), e))),
+        None => match Repository::discover(".") {
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+                return Err("fatal: not a git repository (or any of the parent directories): .git".into());
+            }
+            result => try!(result),
+        },
+    };
+    if let Some(work_tree) = env::var_os("GIT_WORK_TREE") {
+        try!(repo.set_workdir(&try!(env::current_dir()).join(work_tree), false));
+    }
+    Ok(repo)
+}
 
+fn completions(m: &ArgMatches) -> Result<()> {
+    let shell = try!(m.value_of("shell").unwrap().parse::<Shell>());
+    build_app().gen_completions_to("git-series", shell, &mut std::io::stdout());
+    Ok(())
+}
+
+fn roff_escape(line: &str) -> String {
+    let line = line.replace('\\', "\\e");
+    if line.starts_with('.') || line.starts_with('\'') {
+        format!("\\&{}", line)
+    } else {
+        line
+    }
+}
+
+// Write a man page for app, generated from its help text.
+fn write_manpage(dir: &Path, name: &str, app: &App) -> Result<()> {
+    let mut help = Vec::new();
+    try!(app.write_help(&mut help).map_err(|e| e.to_string()));
+    let help = String::from_utf8_lossy(&help).into_owned();
+    let mut paragraphs = help.splitn(2, "\n\n");
+    let header: Vec<&str> = paragraphs.next().unwrap().lines().collect();
+    let about = if header.len() > 1 { header[header.len() - 1] } else { "" };
+    let body = paragraphs.next().unwrap_or("");
+
+    let filename = dir.join(format!("{}.1", name));
+    let mut file = try!(File::create(&filename));
+    try!(writeln!(file, ".TH {} 1 \"\" \"git-series {}\"", name, crate_version!()));
+    try!(writeln!(file, "\n.SH NAME\n{} \\- {}", name, roff_escape(about)));
+    try!(writeln!(file, "\n.SH DESCRIPTION\n.nf"));
+    for line in body.trim_end().lines() {
+        try!(writeln!(file, "{}", roff_escape(line)));
+    }
+    try!(writeln!(file, ".fi\n\n.SH \"SEE ALSO\"\n.BR git-series (1)"));
+    println!("{}", filename.display());
+    Ok(())
+}
+
+fn mangen(m: &ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of_os("dir").unwrap());
+    try!(write_manpage(dir, "git-series", &build_app()));
+    for subcommand in subcommands() {
+        let name = subcommand.get_name().to_string();
+        let subcommand = subcommand.bin_name(format!("git series {}", name));
+        try!(write_manpage(dir, &format!("git-series-{}", name), &subcommand));
+    }
+    Ok(())
+}
+
+fn build_app() -> App<'static, 'static> {
+    App::new("git-series")
+        .bin_name("git series")
+        .about("Track patch series in git")
+        .author("Josh Triplett <josh@joshtriplett.org>")
+        .version(crate_version!())
+        .global_setting(AppSettings::ColoredHelp)
+        .global_setting(AppSettings::UnifiedHelpMessage)
+        .global_setting(AppSettings::VersionlessSubcommands)
+        .setting(AppSettings::AllowExternalSubcommands)
+        .arg(Arg::with_name("C").short("C").value_name("path").multiple(true).number_of_values(1)
+             .help("Run as if git series was started in <path> instead of the current directory"))
+        .arg(Arg::from_usage("-q, --quiet 'Suppress informational messages'").global(true))
+        .arg(Arg::from_usage("--abbrev <n> 'Abbreviate commit ids to at least <n> hex digits'").required(false).global(true))
+        .arg_from_usage("-v, --verbose 'Show more detail, such as refs updated and series descriptions'")
+        .arg_from_usage("--list [pattern] 'List only series whose names match the glob <pattern>'")
+        .arg(Arg::from_usage("--sort [key] 'Sort the list of series by name or by most recent update'").possible_values(&["name", "updated"]))
+        .subcommands(subcommands())
+}
+
+fn subcommands() -> Vec<App<'static, 'static>> {
+    vec![
+        SubCommand::with_name("add")
+            .about("Add changes to the index for the next series commit")
+            .arg(Arg::from_usage("[change]... 'Changes to add'").possible_values(SERIES_CHANGES).required_unless("patch"))
+            .arg_from_usage("-p, --patch 'Show each change that differs from the staged version, and ask whether to add it'"),
+        SubCommand::with_name("base")
+            .about("Get or set the base commit for the patch series")
+            .arg(Arg::with_name("base").help("Base commit").conflicts_with_all(&["delete", "track"]))
+            .arg_from_usage("-d, --delete 'Clear patch series base'")
+            .arg(Arg::from_usage("-f, --force 'Set the base even if it is not an ancestor of the series'").requires("base"))
+            .arg(Arg::from_usage("--track <ref> 'Track ref as the base, following it as it moves'").required(false).conflicts_with("delete")),
+        SubCommand::with_name("checkout")
+            .about("Resume work on a patch series; check out the current version")
+            .arg_from_usage("<name> 'Patch series to check out'")
+            .arg_from_usage("-f, --force 'Discard local modifications to files changed by the checkout'")
+            .arg_from_usage("-b, --branch [branch] 'Create <branch> at the series and switch to it, rather than detaching HEAD'")
+            .arg(Arg::with_name("reset-branch").short("B").takes_value(true).value_name("branch").conflicts_with("branch")
+                 .help("Like --branch, but reset <branch> if it already exists")),
+        SubCommand::with_name("commit")
+            .about("Record changes to the patch series")
+            .arg_from_usage("-a, --all 'Commit all changes'")
+            .arg_from_usage("--author [author] 'Record <author> (\"Name <email>\") as the author of the series commit'")
+            .arg_from_usage("--dry-run 'Check the commit and show what it would commit, without committing'")
+            .arg_from_usage("-m [msg] 'Commit message'")
+            .arg_from_usage("-n, --no-verify 'Don't run the series-pre-commit and series-commit-msg hooks'")
+            .arg_from_usage("-v, --verbose 'Show diff when preparing commit message'")
+            .arg(Arg::from_usage("[change]... 'Commit only these changes, from the working version, without adding them first'").possible_values(SERIES_CHANGES).conflicts_with("all")),
+        SubCommand::with_name("completions")
+            .about("Generate a shell completion script")
+            .setting(AppSettings::Hidden)
+            .arg(Arg::with_name("shell").required(true).possible_values(&["bash", "fish", "zsh"]).help("Shell to generate completions for")),
+        SubCommand::with_name("config")
+            .about("Get or set configuration for the patch series")
+            .arg(Arg::with_name("key").required(true).possible_values(SERIES_CONFIG_KEYS).help("Configuration key"))
+            .arg(Arg::with_name("value").help("New value; without this, show the current value"))
+            .arg(Arg::from_usage("--add 'Add a value rather than replacing existing values'").requires("value"))
+            .arg(Arg::from_usage("--unset 'Remove all values of <key>'").conflicts_with_all(&["value", "add"])),
+        SubCommand::with_name("cover")
+            .about("Create or edit the cover letter for the patch series")
+            .arg_from_usage("-d, --delete 'Delete cover letter'"),
+        SubCommand::with_name("cp")
+            .about("Copy a patch series")
+            .arg(Arg::with_name("source_dest").required(true).min_values(1).max_values(2).help("source (default: current series) and destination (required)")),
+        SubCommand::with_name("delete")
+            .about("Delete a patch series")
+            .arg_from_usage("<name> 'Patch series to delete'"),
+        SubCommand::with_name("describe")
+            .about("Create or edit the description of the patch series")
+            .arg_from_usage("-d, --delete 'Delete description'"),
+        SubCommand::with_name("detach")
+            .about("Stop working on any patch series")
+            .arg_from_usage("-f, --force 'Detach even if the series has uncommitted changes'"),
+        SubCommand::with_name("diff")
+            .about("Show changes in the patch series, or the difference between two series")
+            .arg(Arg::with_name("series").min_values(2).max_values(2).help("Two patch series whose tips to compare")),
+        SubCommand::with_name("export")
+            .about("Create a branch pointing to the tip of a patch series")
+            .arg_from_usage("-f, --force 'Overwrite <branch> if it already exists'")
+            .arg_from_usage("<name> 'Patch series to export'")
+            .arg_from_usage("<branch> 'Branch to create'"),
+        SubCommand::with_name("format")
+            .about("Prepare patch series for email")
+            .arg_from_usage("--allow-merges 'Format merge commits as a diff against their first parent rather than failing'")
+            .arg_from_usage("--base [upstream] 'Record <upstream> as the base commit, listing patches between it and the series base as prerequisites'")
+            .arg_from_usage("--cc-from-trailers 'Address each patch to the To: and Cc: trailers in its commit message'")
+            .arg(Arg::from_usage("--cover-letter 'Include a cover letter, with the series name as the subject if the series has none'").conflicts_with("no-cover-letter"))
+            .arg_from_usage("--no-cover-letter 'Don't include a cover letter, even if the series has one'")
+            .arg_from_usage("--date [date] 'Use <date> (RFC 2822, or @<seconds since the epoch>) as the date of every mail'")
+            .arg_from_usage("--filename-max-length [N] 'Limit the names of patch files to N bytes, instead of 64'")
+            .arg(Arg::from_usage("--from [ident] 'Send the mails as <ident> (\"Name <email>\") rather than as the committer'").conflicts_with("no-from"))
+            .arg_from_usage("--in-reply-to [Message-Id] 'Make the first mail a reply to the specified Message-Id'")
+            .arg_from_usage("--interdiff [rev] 'Include an interdiff against the previous version <rev> in the cover letter'")
+            .arg_from_usage("--keep-subject 'Use the first line of each commit message as its subject exactly, without trimming it'")
+            .arg(Arg::from_usage("--manifest [file] 'Write a JSON description of each mail to <file>'").conflicts_with_all(&["dry-run", "send-email"]))
+            .arg(Arg::from_usage("-n, --numbered 'Number the patches in mail subjects even for a single patch'").conflicts_with("no-numbered"))
+            .arg_from_usage("-N, --no-numbered 'Don't number the patches in mail subjects'")
+            .arg_from_usage("--no-from 'Don't include in-body \"From:\" headers when formatting patches authored by others'")
+            .arg_from_usage("--no-signature 'Don't append a signature to each mail'")
+            .arg(Arg::from_usage("--signature [text] 'Use <text> as the signature of each mail'").conflicts_with("no-signature"))
+            .arg_from_usage("-v, --reroll-count=[N] 'Mark the patch series as PATCH vN'")
+            .arg_from_usage("--range-diff [rev] 'Include a range-diff against the previous version <rev> in the cover letter'")
+            .arg(Arg::from_usage("--rfc 'Use [RFC PATCH] instead of the standard [PATCH] prefix'").conflicts_with("subject-prefix"))
+            .arg(Arg::from_usage("--dry-run 'Print the names of the patch files without writing them'").conflicts_with_all(&["stdout", "mbox", "send-email"]))
+            .arg(Arg::from_usage("--mbox [file] 'Write all patches to a single mbox file'").conflicts_with_all(&["stdout", "send-email"]))
+            .arg(Arg::from_usage("--send-email 'Send the patches with \"git send-email\" rather than writing files'").conflicts_with("stdout"))
+            .arg(Arg::from_usage("-s, --signoff 'Add a Signed-off-by trailer for the committer to each mail, unless already present'").conflicts_with("no-signoff"))
+            .arg_from_usage("--no-signoff 'Don't add a Signed-off-by trailer, overriding format.signOff'")
+            .arg(Arg::from_usage("--signoff-cc 'With --cc-from-trailers, also Cc: each patch to its Signed-off-by: trailers'").requires("cc-from-trailers"))
+            .arg_from_usage("--start-number [N] 'Start numbering the patches at N instead of 1'")
+            .arg_from_usage("--stdout 'Write patches to stdout rather than files'")
+            .arg_from_usage("--subject-prefix [Subject-Prefix] 'Use [Subject-Prefix] instead of the standard [PATCH] prefix'")
+            .arg_from_usage("--total [M] 'Use M as the total number of patches in mail subjects'")
+            .arg(Arg::from_usage("--transfer-encoding [encoding] 'Use <encoding> as the Content-Transfer-Encoding of each mail'").possible_values(&["7bit", "8bit", "quoted-printable", "base64"]))
+            .arg(Arg::from_usage("--whitespace [action] 'Check the lines each patch adds for whitespace errors, and warn, fail, or fix them'").possible_values(&["warn", "error", "fix"]))
+            .arg(Arg::from_usage("--to [address] 'Add a To: header with <address> to each mail'").multiple(true).number_of_values(1))
+            .arg(Arg::from_usage("--cc [address] 'Add a Cc: header with <address> to each mail'").multiple(true).number_of_values(1)),
+        SubCommand::with_name("import")
+            .about("Start a patch series from an existing branch")
+            .arg_from_usage("--base [base] 'Base commit for the series (default: merge base with the upstream of <branch>)'")
+            .arg_from_usage("--name [name] 'Name of the new series (default: <branch>)'")
+            .arg_from_usage("<branch> 'Branch to import'"),
+        SubCommand::with_name("log")
+            .about("Show the history of the patch series")
+            .arg_from_usage("-p, --patch 'Include a patch for each change committed to the series'")
+            .arg_from_usage("--stat 'Include a diffstat for each change committed to the series'"),
+        SubCommand::with_name("mangen")
+            .about("Generate man pages for git series and its subcommands")
+            .setting(AppSettings::Hidden)
+            .arg_from_usage("<dir> 'Directory to write the man pages to'"),
+        SubCommand::with_name("mv")
+            .about("Move (rename) a patch series")
+            .visible_alias("rename")
+            .arg(Arg::with_name("source_dest").required(true).min_values(1).max_values(2).help("source (default: current series) and destination (required)")),
+        SubCommand::with_name("patch-id")
+            .about("Show the stable patch-id of each patch in the series")
+            .arg_from_usage("[rev] 'Version of the series to show, as a series commit or the last commit of the series (default: current version)'"),
+        SubCommand::with_name("prune")
+            .about("Delete leftover staged and working versions of series that have no commits")
+            .arg_from_usage("-n, --dry-run 'Show what would be pruned without deleting anything'")
+            .arg_from_usage("--uncommitted 'Also delete series that were started but never committed'"),
+        SubCommand::with_name("rebase")
+            .about("Rebase the patch series")
+            .arg_from_usage("[onto] 'Commit to rebase onto'")
+            .arg_from_usage("-i, --interactive 'Interactively edit the list of commits'")
+            .arg_from_usage("--edit-cover 'Edit the cover letter after rebasing'")
+            .arg_from_usage("--keep-base 'Rebase onto the merge base of the current base and the upstream, rather than the upstream tip'")
+            .arg(Arg::with_name("newbase").long("onto").takes_value(true).value_name("newbase").requires("onto").conflicts_with("keep-base")
+                 .help("Rebase onto <newbase> only the commits after <onto>, which names the upstream rather than the commit to rebase onto"))
+            .arg(Arg::from_usage("--root 'Rebase all the commits of the series back to the root of history, rather than those after the base'")
+                 .conflicts_with_all(&["newbase", "keep-base"]))
+            .group(ArgGroup::with_name("action").args(&["onto", "interactive"]).multiple(true)),
+        SubCommand::with_name("reflog")
+            .about("Show the reflog of a patch series")
+            .arg_from_usage("[name] 'Patch series to show the reflog of (default: current series)'")
+            .arg_from_usage("--internals 'Also show the reflogs of the staged and working versions'")
+            .arg_from_usage("-n [N] 'Limit each reflog to the N most recent entries'"),
+        SubCommand::with_name("req")
+            .about("Generate a mail requesting a pull of the patch series")
+            .visible_aliases(&["pull-request", "request-pull"])
+            .arg_from_usage("--compose 'Edit the message in an editor before writing the mail'")
+            .arg_from_usage("--in-reply-to [Message-Id] 'Make the mail a reply to the specified Message-Id'")
+            .arg_from_usage("--no-signature 'Don't append a signature to the mail'")
+            .arg(Arg::from_usage("--signature [text] 'Use <text> as the signature of the mail'").conflicts_with("no-signature"))
+            .arg_from_usage("-o, --output [file] 'Write the mail to <file> rather than stdout'")
+            .arg_from_usage("-p, --patch 'Include patch in the mail'")
+            .arg_from_usage("--pull-url [url] 'Repository URL to advertise in the mail, if different from the URL to connect to'")
+            .arg_from_usage("-s, --signoff 'Add a Signed-off-by trailer for the committer to the message'")
+            .arg_from_usage("[url] 'Repository URL to request pull of (default: series config \"url\", or the URL of the default push remote)'")
+            .arg_from_usage("[tag] 'Tag or branch name to request pull of (default: series config \"tag\", or the series name)'"),
+        SubCommand::with_name("status")
+            .about("Show the status of the patch series")
+            .arg_from_usage("[name] 'Patch series to show (default: current series)'"),
+        SubCommand::with_name("start")
+            .about("Start a new patch series")
+            .arg_from_usage("<name> 'Patch series name'"),
+        SubCommand::with_name("undo")
+            .about("Undo the last change to the current patch series"),
+        SubCommand::with_name("unadd")
+            .about("Undo \"git series add\", removing changes from the next series commit")
+            .arg(Arg::from_usage("<change>... 'Changes to remove'").possible_values(SERIES_CHANGES)),
+    ]
+}
+
+// Split an alias definition into arguments, honoring single and double quotes and backslash
+// escapes the way git does.
+fn split_cmdline(cmdline: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = cmdline.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::replace(&mut arg, String::new()));
+                    in_arg = false;
+                }
+                continue;
+            }
+            (None, '"') | (None, '\'') => { quote = Some(c); }
+            (Some(q), c) if c == q => { quote = None; }
+            (Some('\''), c) => { arg.push(c); }
+            (_, '\\') => { arg.push(try!(chars.next().ok_or("cmdline ends with \\"))); }
+            (_, c) => { arg.push(c); }
+        }
+        in_arg = true;
+    }
+    if quote.is_some() {
+        return Err("unclosed quote".into());
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+#[test]
+fn test_split_cmdline() {
+    let tests = vec![
+        ("", vec![]),
+        ("checkout", vec!["checkout"]),
+        ("  rebase   -i ", vec!["rebase", "-i"]),
+        ("commit -m 'two words'", vec!["commit", "-m", "two words"]),
+        ("commit -m \"say \\\"hi\\\"\"", vec!["commit", "-m", "say \"hi\""]),
+        ("a\\ b ''", vec!["a b", ""]),
+    ];
+    for (cmdline, args) in tests {
+        assert_eq!(split_cmdline(cmdline).unwrap(), args);
+    }
+    assert!(split_cmdline("'unclosed").is_err());
+}
+
+// Expand a series.alias.<cmd> alias. Aliases starting with '!' run as shell commands; others
+// re-run git series with the alias expansion in place of the alias name.
+fn alias(out: &mut Output, repo: &Repository, cmd: &str, m: &ArgMatches, expanded_aliases: &mut Vec<String>) -> Result<()> {
+    let config = try!(repo.config());
+    let key = format!("series.alias.{}", cmd);
+    let value = try!(try!(notfound_to_none(config.get_string(&key)))
+                     .ok_or_else(|| format!("'{}' is not a git series command. See 'git series --help'.", cmd)));
+    if expanded_aliases.iter().any(|a| a == cmd) {
+        return Err(format!("alias loop detected: expansion of '{}' does not terminate", cmd).into());
+    }
+    expanded_aliases.push(cmd.to_string());
+    let extra_args: Vec<OsString> = m.values_of_os("").map_or(Vec::new(), |v| v.map(OsStr::to_os_string).collect());
+
+    if value.starts_with('!') {
+        let mut command = cmd_maybe_shell(&value[1..], true);
+        if let Some(workdir) = repo.workdir() {
+            command.current_dir(workdir);
+        }
+        let status = try!(command.args(&extra_args).status());
+        if !status.success() {
+            return Err(format!("Alias '{}' exited with status {}", cmd, status).into());
+        }
+        return Ok(());
+    }
+
+    let mut args: Vec<OsString> = vec!["git-series".into()];
+    args.extend(try!(split_cmdline(&value).map_err(|e| format!("Bad alias {}: {}", key, e))).into_iter().map(OsString::from));
+    args.extend(extra_args);
+    let m = try!(parse_args(args));
+    git_series(out, repo, &m, expanded_aliases)
+}
+
+fn git_series(out: &mut Output, repo: &Repository, m: &ArgMatches, expanded_aliases: &mut Vec<String>) -> Result<()> {
+    // --quiet is global, so it may appear after the subcommand name.
+    let quiet = m.is_present("quiet") || m.subcommand().1.map_or(false, |sm| sm.is_present("quiet"));
+    // The minimum length of abbreviated object ids, from --abbrev; 0 to use core.abbrev.
+    let abbrev = match m.value_of("abbrev").or_else(|| m.subcommand().1.and_then(|sm| sm.value_of("abbrev"))) {
+        Some(n) => max(try!(n.parse::<usize>().map_err(|e| format!("Invalid abbreviation length \"{}\": {}", n, e))), 4),
+        None => 0,
+    };
+    let verbosity = if quiet {
+        Verbosity::Quiet
+    } else if m.is_present("verbose") {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    // Record the state before any change, for git series undo. Aliases record their expansion.
+    let undo_command = format!("git series {}", m.subcommand_name().unwrap_or("")).trim().to_string();
+    let undo_before = try!(undo_state(repo, None));
+    let undo_record_id = try!(undo_record(repo, &undo_before, &undo_command));
+    let result = match m.subcommand() {
+        ("", _) => series(out, repo, m, verbosity, abbrev),
+        ("add", Some(ref sm)) => add(repo, &sm, verbosity, abbrev),
+        ("base", Some(ref sm)) => base(repo, &sm, verbosity, abbrev),
+        ("checkout", Some(ref sm)) => checkout(repo, &sm, verbosity, abbrev),
+        ("commit", Some(ref sm)) => commit_status(out, repo, &sm, false, verbosity, abbrev),
+        ("completions", Some(ref sm)) => completions(&sm),
+        ("config", Some(ref sm)) => do_config(repo, &sm, verbosity),
+        ("cover", Some(ref sm)) => cover(repo, &sm, verbosity),
+        ("cp", Some(ref sm)) => cp_mv(repo, &sm, false),
+        ("delete", Some(ref sm)) => delete(repo, &sm),
+        ("describe", Some(ref sm)) => describe(repo, &sm, verbosity),
+        ("detach", Some(ref sm)) => detach(repo, &sm),
+        ("diff", Some(ref sm)) => do_diff(out, repo, &sm, abbrev),
+        ("export", Some(ref sm)) => export(repo, &sm, verbosity, abbrev),
+        ("format", Some(ref sm)) => format(out, repo, &sm, verbosity, abbrev),
+        ("import", Some(ref sm)) => import(repo, &sm, verbosity, abbrev),
+        ("log", Some(ref sm)) => log(out, repo, &sm, abbrev),
+        ("mangen", Some(ref sm)) => mangen(&sm),
+        ("mv", Some(ref sm)) => cp_mv(repo, &sm, true),
+        ("patch-id", Some(ref sm)) => patch_ids(out, repo, &sm, abbrev),
+        ("prune", Some(ref sm)) => prune(repo, &sm, verbosity),
+        ("rebase", Some(ref sm)) => rebase(repo, &sm, verbosity, abbrev),
+        ("reflog", Some(ref sm)) => reflog(out, repo, &sm, abbrev),
+        ("req", Some(ref sm)) => req(out, repo, &sm, abbrev),
+        ("start", Some(ref sm)) => start(repo, &sm, verbosity, abbrev),
+        ("status", Some(ref sm)) => commit_status(out, repo, &sm, true, verbosity, abbrev),
+        ("unadd", Some(ref sm)) => unadd(repo, &sm, verbosity),
+        ("undo", _) => undo(repo, verbosity),
+        (cmd, Some(ref sm)) => { return alias(out, repo, cmd, &sm, expanded_aliases); }
+        _ => unreachable!()
+    };
+    // Record even a failed command, which may have changed some refs before failing.
+    let recorded = record_undo(repo, &undo_before, undo_record_id, &undo_command);
+    result.and(recorded)
+}
+
+// Parse the command line, exiting directly for --help and --version and returning usage errors.
+fn parse_args<I, T>(args: I) -> Result<ArgMatches<'static>> where I: IntoIterator<Item = T>, T: Into<OsString> + Clone {
+    match build_app().get_matches_from_safe(args) {
+        Err(ref e) if !e.use_stderr() => e.exit(),
+        m => Ok(try!(m)),
+    }
+}
+
+fn main() {
     let mut out = Output::new();
 
     let err = || -> Result<()> {
-        let repo = try!(Repository::discover("."));
+        let m = try!(parse_args(env::args_os()));
+        if let Some(dirs) = m.values_of_os("C") {
+            for dir in dirs {
+                try!(env::set_current_dir(dir).map_err(|e| format!("fatal: cannot change to '{}': {}", dir.to_string_lossy(), e)));
+            }
+        }
         match m.subcommand() {
-            ("", _) => series(&mut out, &repo),
-            ("add", Some(ref sm)) => add(&repo, &sm),
-            ("base", Some(ref sm)) => base(&repo, &sm),
-            ("checkout", Some(ref sm)) => checkout(&repo, &sm),
-            ("commit", Some(ref sm)) => commit_status(&mut out, &repo, &sm, false),
-            ("cover", Some(ref sm)) => cover(&repo, &sm),
-            ("cp", Some(ref sm)) => cp_mv(&repo, &sm, false),
-            ("delete", Some(ref sm)) => delete(&repo, &sm),
-            ("detach", _) => detach(&repo),
-            ("diff", _) => do_diff(&mut out, &repo),
-            ("format", Some(ref sm)) => format(&mut out, &repo, &sm),
-            ("log", Some(ref sm)) => log(&mut out, &repo, &sm),
-            ("mv", Some(ref sm)) => cp_mv(&repo, &sm, true),
-            ("rebase", Some(ref sm)) => rebase(&repo, &sm),
-            ("req", Some(ref sm)) => req(&mut out, &repo, &sm),
-            ("start", Some(ref sm)) => start(&repo, &sm),
-            ("status", Some(ref sm)) => commit_status(&mut out, &repo, &sm, true),
-            ("unadd", Some(ref sm)) => unadd(&repo, &sm),
-            _ => unreachable!()
+            ("completions", Some(ref sm)) => { return completions(&sm); }
+            ("mangen", Some(ref sm)) => { return mangen(&sm); }
+            _ => {}
         }
+        let repo = try!(discover_repo());
+        git_series(&mut out, &repo, &m, &mut Vec::new())
     }();
 
     if let Err(e) = err {
         let msg = e.to_string();
         out.write_err(&format!("{}{}", msg, ensure_nl(&msg)));
         drop(out);
-        std::process::exit(1);
+        std::process::exit(e.exit_code());
     }
 }